@@ -0,0 +1,160 @@
+use std::error::Error;
+use std::f64::consts::PI;
+
+use errors;
+use math;
+
+use super::{Eval, Expr, Variables};
+use super::variables::distance;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Type {
+    Scalar,
+    Point,
+    Line,
+}
+
+use self::Type::*;
+
+/// The built-in functions, the types of their arguments, and the types of
+/// their results.
+const FUNCTIONS: &[(&str, &[Type], Type)] = &[
+    ("pi", &[], Scalar),
+    ("E", &[], Scalar),
+    ("SE", &[], Scalar),
+    ("S", &[], Scalar),
+    ("SW", &[], Scalar),
+    ("W", &[], Scalar),
+    ("NW", &[], Scalar),
+    ("N", &[], Scalar),
+    ("NE", &[], Scalar),
+    ("sqrt", &[Scalar], Scalar),
+    ("abs", &[Scalar], Scalar),
+    ("floor", &[Scalar], Scalar),
+    ("round", &[Scalar], Scalar),
+    ("sin", &[Scalar], Scalar),
+    ("cos", &[Scalar], Scalar),
+    ("tan", &[Scalar], Scalar),
+    ("atan2", &[Scalar, Scalar], Scalar),
+    ("min", &[Scalar, Scalar], Scalar),
+    ("max", &[Scalar, Scalar], Scalar),
+    ("distance", &[Point, Point], Scalar),
+    ("angle", &[Line], Scalar),
+    ("midpoint", &[Point, Point], Point),
+    ("lerp", &[Point, Point, Scalar], Point),
+    ("rotate", &[Point, Point, Scalar], Point),
+    ("project", &[Point, Line], Point),
+    ("polar", &[Scalar, Scalar], Point),
+    ("bisector", &[Line, Line], Line),
+];
+
+/// Calls the built-in function `name`.
+///
+/// Angles are in degrees, turning from the x axis towards the y axis, so
+/// clockwise on the map since y increases downwards. Functions returning an
+/// angle (`atan2` and `angle`) give a value from 0 up to 360, and the
+/// octilinear directions `E`, `SE`, `S`, `SW`, `W`, `NW`, `N` and `NE` are
+/// 0, 45, ..., 315 accordingly.
+pub fn call(name: &str, args: &[Expr], vars: &Variables) -> Result<math::Expr, Box<Error>> {
+    let types = match FUNCTIONS.iter().find(|&&(f, _, _)| f == name) {
+        Some(&(_, types, _)) => types,
+        None => Err(unknown(name))?,
+    };
+    if args.len() != types.len() {
+        Err(errors::Error::function_args(name, args.len(), types.len()))?;
+    }
+    let mut x = Vec::new();
+    let mut p = Vec::new();
+    let mut l = Vec::new();
+    for (i, (arg, &type_)) in args.iter().zip(types.iter()).enumerate() {
+        match (arg.eval(vars)?, type_) {
+            (math::Expr::Scalar(val), Scalar) => x.push(val),
+            (math::Expr::Point(val), Point) => p.push(val),
+            (math::Expr::Line(val), Line) => l.push(val),
+            _ => {
+                let err = errors::Error::function_arg_type(name, i + 1, type_name(type_));
+                Err(err.at(arg.span()))?
+            },
+        }
+    }
+    let scalar = |val| Ok(math::Expr::Scalar(val));
+    let point = |val| Ok(math::Expr::Point(val));
+    match name {
+        "pi" => scalar(PI),
+        "E" => scalar(0.0),
+        "SE" => scalar(45.0),
+        "S" => scalar(90.0),
+        "SW" => scalar(135.0),
+        "W" => scalar(180.0),
+        "NW" => scalar(225.0),
+        "N" => scalar(270.0),
+        "NE" => scalar(315.0),
+        "sqrt" => scalar(x[0].sqrt()),
+        "abs" => scalar(x[0].abs()),
+        "floor" => scalar(x[0].floor()),
+        "round" => scalar(x[0].round()),
+        "sin" => scalar(x[0].to_radians().sin()),
+        "cos" => scalar(x[0].to_radians().cos()),
+        "tan" => scalar(x[0].to_radians().tan()),
+        "atan2" => scalar(direction(x[0], x[1])),
+        "min" => scalar(x[0].min(x[1])),
+        "max" => scalar(x[0].max(x[1])),
+        "distance" => scalar((p[1] - p[0]).abs()),
+        "angle" => scalar(direction(l[0].vector.1, l[0].vector.0)),
+        "midpoint" => point((p[0] + p[1]) / 2.0),
+        "lerp" => point(p[0] + (p[1] - p[0]) * x[0]),
+        "rotate" => point(p[0].rotate(p[1], x[0].to_radians())),
+        "project" => point(l[0].project(p[0])),
+        "polar" => point(math::Point::polar(x[0], x[1].to_radians())),
+        "bisector" => {
+            let line = l[0].bisector(l[1]).ok_or_else(|| {
+                errors::Error::parallel(format!("{}", l[0]).as_ref(),
+                                        format!("{}", l[1]).as_ref())
+            })?;
+            Ok(math::Expr::Line(line))
+        },
+        _ => Err(unknown(name))?,
+    }
+}
+
+/// Returns the angle of the vector `(x, y)`, from 0 up to 360 degrees.
+fn direction(y: f64, x: f64) -> f64 {
+    (y.atan2(x).to_degrees() + 360.0) % 360.0
+}
+
+fn type_name(type_: Type) -> &'static str {
+    match type_ {
+        Scalar => "scalar",
+        Point => "point",
+        Line => "line",
+    }
+}
+
+/// Returns the type of the result of the built-in function `name`. Unknown
+/// names take the type of the closest function, so that a misspelled call
+/// parses where that function could be used, and is then reported with
+/// suggestions.
+pub fn result_type(name: &str) -> Option<Type> {
+    match FUNCTIONS.iter().find(|&&(f, _, _)| f == name) {
+        Some(&(_, _, type_)) => Some(type_),
+        None => closest(name).first().map(|f| f.2),
+    }
+}
+
+/// Returns the functions with names similar to `name`, closest first.
+fn closest(name: &str) -> Vec<&'static (&'static str, &'static [Type], Type)> {
+    let mut close = FUNCTIONS.iter()
+        .map(|f| (distance(f.0, name), f))
+        .filter(|&(d, _)| d <= (name.chars().count() / 3).max(1))
+        .collect::<Vec<_>>();
+    close.sort_by_key(|&(d, _)| d);
+    close.into_iter().map(|(_, f)| f).collect()
+}
+
+fn unknown(name: &str) -> errors::Error {
+    let suggestions = closest(name).into_iter()
+        .take(3)
+        .map(|&(f, _, _)| String::from(f))
+        .collect();
+    errors::Error::unknown_function(name, suggestions)
+}
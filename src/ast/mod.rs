@@ -8,9 +8,14 @@ use route;
 use stop;
 
 mod variables;
+mod functions;
+mod order;
 
 use errors;
+use errors::Span;
 pub use self::variables::Variables;
+pub use self::order::order;
+pub use self::functions::{result_type, Type};
 
 #[derive(Clone, Debug)]
 pub enum Ident {
@@ -49,20 +54,56 @@ macro_rules! ident {
 
             fn from_str(s: &str) -> Result<Self, Self::Err> {
                 lazy_static! {
-                    static ref RE: Regex = Regex::new(concat!($prefix_re, r"(\w+)")).unwrap();
+                    static ref RE: Regex = Regex::new(
+                        concat!($prefix_re, r"((?:\w+::)*(?:\w|\{\$\w+\})+)")).unwrap();
                 }
                 RE.captures(s).map_or(
                     Err(errors::Error::ident_type(s, $name)),
                     |caps| Ok($type(String::from(caps.get(1).unwrap().as_str()))))
             }
         }
+
+        impl $type {
+            /// Replaces each `{$var}` in the name with the value of `$var`.
+            pub fn interpolate(&self, vars: &Variables) -> Result<$type, Box<Error>> {
+                if self.0.contains('{') {
+                    Ok($type(interpolate(&format!("{}", self), &self.0, vars)?))
+                } else {
+                    Ok(self.clone())
+                }
+            }
+        }
     };
 }
 
+/// Substitutes scalar values into `name`, which is part of the identifier
+/// `ident`.
+fn interpolate(ident: &str, name: &str, vars: &Variables) -> Result<String, Box<Error>> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\{(\$\w+)\}").unwrap();
+    }
+    let mut out = String::new();
+    let mut last = 0;
+    for caps in RE.captures_iter(name) {
+        let (all, var) = (caps.get(0).unwrap(), caps.get(1).unwrap().as_str());
+        let val = *vars.get_scalar(&SIdent::from_str(var)?)?;
+        if val.fract() != 0.0 {
+            Err(errors::Error::interpolation(ident, var, val))?;
+        }
+        out.push_str(&name[last..all.start()]);
+        out.push_str(&format!("{}", val as i64));
+        last = all.end();
+    }
+    out.push_str(&name[last..]);
+    Ok(out)
+}
+
 ident!{SIdent, "Scalar", "$", r"\$"}
 ident!{PIdent, "Scalar", "@"}
 ident!{LIdent, "Scalar", "!"}
 ident!{RIdent, "Scalar", "~"}
+ident!{StIdent, "Stop", "%"}
+ident!{StrIdent, "String", "#"}
 
 pub trait Eval {
     type Output;
@@ -94,18 +135,41 @@ impl Eval for Expr {
     }
 }
 
+impl Expr {
+    fn type_name(&self) -> &'static str {
+        match *self {
+            Expr::Scalar(_) => "Scalar",
+            Expr::Point(_) => "Point",
+            Expr::Line(_) => "Line",
+        }
+    }
+
+    pub fn span(&self) -> Span {
+        match *self {
+            Expr::Scalar(ref x) => x.span(),
+            Expr::Point(ref x) => x.span(),
+            Expr::Line(ref x) => x.span(),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum Scalar {
-    Add(Box<Scalar>, Box<Scalar>),
-    Neg(Box<Scalar>),
-    Mul(Box<Scalar>, Box<Scalar>),
-    Div(Box<Scalar>, Box<Scalar>),
-    Less(Box<Scalar>, Box<Scalar>),
-    LessEq(Box<Scalar>, Box<Scalar>),
-    Equal(Box<Scalar>, Box<Scalar>),
-    Num(math::Scalar),
-    Ident(SIdent),
-    Macro(SIdent, Vec<Expr>),
+    Add(Box<Scalar>, Box<Scalar>, Span),
+    Neg(Box<Scalar>, Span),
+    Mul(Box<Scalar>, Box<Scalar>, Span),
+    Div(Box<Scalar>, Box<Scalar>, Span),
+    Less(Box<Scalar>, Box<Scalar>, Span),
+    LessEq(Box<Scalar>, Box<Scalar>, Span),
+    Equal(Box<Scalar>, Box<Scalar>, Span),
+    And(Box<Scalar>, Box<Scalar>, Span),
+    Or(Box<Scalar>, Box<Scalar>, Span),
+    Not(Box<Scalar>, Span),
+    If(Box<Scalar>, Box<Scalar>, Box<Scalar>, Span),
+    Num(math::Scalar, Span),
+    Ident(SIdent, Span),
+    Macro(SIdent, Vec<Arg>, Span),
+    Call(String, Vec<Expr>, Span),
 }
 
 impl Eval for Scalar {
@@ -116,99 +180,170 @@ impl Eval for Scalar {
     fn eval(&self, vars: &Variables) -> Result<math::Scalar, Box<Error>> {
         use self::Scalar::*;
         match *self {
-            Add(ref a, ref b) => Ok(a.eval(vars)? + b.eval(vars)?),
-            Neg(ref a) => Ok(-a.eval(vars)?),
-            Mul(ref a, ref b) => Ok(a.eval(vars)? * b.eval(vars)?),
-            Div(ref a, ref b) => Ok(a.eval(vars)? / b.eval(vars)?),
-            Less(ref a, ref b) => if a.eval(vars)? < b.eval(vars)? {Ok(1.0)} else {Ok(0.0)},
-            LessEq(ref a, ref b) => if a.eval(vars)? <= b.eval(vars)? {Ok(1.0)} else {Ok(0.0)},
-            Equal(ref a, ref b) => if a.eval(vars)? == b.eval(vars)? {Ok(1.0)} else {Ok(0.0)},
-            Num(x) => Ok(x),
-            Ident(ref id) => Ok(*vars.get_scalar(id)?),
-            Macro(ref id, ref args) => vars.get_scalar_macro(id)?.expand(args, vars),
+            Add(ref a, ref b, _) => Ok(a.eval(vars)? + b.eval(vars)?),
+            Neg(ref a, _) => Ok(-a.eval(vars)?),
+            Mul(ref a, ref b, _) => Ok(a.eval(vars)? * b.eval(vars)?),
+            Div(ref a, ref b, _) => Ok(a.eval(vars)? / b.eval(vars)?),
+            Less(ref a, ref b, _) => if a.eval(vars)? < b.eval(vars)? {Ok(1.0)} else {Ok(0.0)},
+            LessEq(ref a, ref b, _) => if a.eval(vars)? <= b.eval(vars)? {Ok(1.0)} else {Ok(0.0)},
+            Equal(ref a, ref b, _) => if a.eval(vars)? == b.eval(vars)? {Ok(1.0)} else {Ok(0.0)},
+            And(ref a, ref b, _) => {
+                if a.eval(vars)? != 0.0 && b.eval(vars)? != 0.0 {Ok(1.0)} else {Ok(0.0)}
+            },
+            Or(ref a, ref b, _) => {
+                if a.eval(vars)? != 0.0 || b.eval(vars)? != 0.0 {Ok(1.0)} else {Ok(0.0)}
+            },
+            Not(ref a, _) => if a.eval(vars)? == 0.0 {Ok(1.0)} else {Ok(0.0)},
+            If(ref c, ref a, ref b, _) => {
+                if c.eval(vars)? != 0.0 {a.eval(vars)} else {b.eval(vars)}
+            },
+            Num(x, _) => Ok(x),
+            Ident(ref id, span) => {
+                Ok(*vars.get_scalar(id).map_err(|e| errors::locate(e, span))?)
+            },
+            Macro(ref id, ref args, span) => {
+                vars.get_scalar_macro(id)
+                    .and_then(|(m, env)| m.expand(args, span, vars, env))
+                    .map_err(|e| errors::locate(e, span))
+            },
+            Call(ref name, ref args, span) => {
+                match functions::call(name, args, vars).map_err(|e| errors::locate(e, span))? {
+                    math::Expr::Scalar(x) => Ok(x),
+                    val => Err(errors::Error::function_type(name, val.type_name(), "scalar").at(span))?,
+                }
+            },
         }
     }
 }
 
 impl Scalar {
-    pub fn lt(self, b: Scalar) -> Scalar {
+    pub fn span(&self) -> Span {
         use self::Scalar::*;
-        if let (&Num(n), &Num(m)) = (&self, &b) {
-            Num(if n < m {1.0} else {0.0})
+        match *self {
+            Add(.., span) | Neg(.., span) | Mul(.., span) | Div(.., span) | Less(.., span) |
+            LessEq(.., span) | Equal(.., span) | And(.., span) | Or(.., span) |
+            Not(.., span) | If(.., span) | Num(.., span) | Ident(.., span) |
+            Macro(.., span) | Call(.., span) => span,
+        }
+    }
+
+    pub fn lt(self, b: Scalar, span: Span) -> Scalar {
+        use self::Scalar::*;
+        if let (&Num(n, _), &Num(m, _)) = (&self, &b) {
+            Num(if n < m {1.0} else {0.0}, span)
+        } else {
+            Less(Box::new(self), Box::new(b), span)
+        }
+    }
+
+    pub fn le(self, b: Scalar, span: Span) -> Scalar {
+        use self::Scalar::*;
+        if let (&Num(n, _), &Num(m, _)) = (&self, &b) {
+            Num(if n <= m {1.0} else {0.0}, span)
+        } else {
+            LessEq(Box::new(self), Box::new(b), span)
+        }
+    }
+
+    pub fn eq(self, b: Scalar, span: Span) -> Scalar {
+        use self::Scalar::*;
+        if let (&Num(n, _), &Num(m, _)) = (&self, &b) {
+            Num(if n == m {1.0} else {0.0}, span)
         } else {
-            Less(Box::new(self), Box::new(b))
+            Equal(Box::new(self), Box::new(b), span)
         }
     }
 
-    pub fn le(self, b: Scalar) -> Scalar {
+    pub fn and(self, b: Scalar, span: Span) -> Scalar {
         use self::Scalar::*;
-        if let (&Num(n), &Num(m)) = (&self, &b) {
-            Num(if n <= m {1.0} else {0.0})
+        if let (&Num(n, _), &Num(m, _)) = (&self, &b) {
+            Num(if n != 0.0 && m != 0.0 {1.0} else {0.0}, span)
         } else {
-            LessEq(Box::new(self), Box::new(b))
+            And(Box::new(self), Box::new(b), span)
         }
     }
 
-    pub fn eq(self, b: Scalar) -> Scalar {
+    pub fn or(self, b: Scalar, span: Span) -> Scalar {
         use self::Scalar::*;
-        if let (&Num(n), &Num(m)) = (&self, &b) {
-            Num(if n == m {1.0} else {0.0})
+        if let (&Num(n, _), &Num(m, _)) = (&self, &b) {
+            Num(if n != 0.0 || m != 0.0 {1.0} else {0.0}, span)
         } else {
-            Equal(Box::new(self), Box::new(b))
+            Or(Box::new(self), Box::new(b), span)
+        }
+    }
+
+    pub fn not(self, span: Span) -> Scalar {
+        use self::Scalar::*;
+        match self {
+            Num(n, _) => Num(if n == 0.0 {1.0} else {0.0}, span),
+            _ => Not(Box::new(self), span),
+        }
+    }
+
+    /// Uses `self` as a condition, choosing `a` if it is nonzero, and `b`
+    /// otherwise.
+    pub fn cond(self, a: Scalar, b: Scalar, span: Span) -> Scalar {
+        use self::Scalar::*;
+        match self {
+            Num(n, _) => if n != 0.0 {a} else {b},
+            _ => If(Box::new(self), Box::new(a), Box::new(b), span),
         }
     }
 
-    pub fn add(self, b: Scalar) -> Scalar {
+    pub fn add(self, b: Scalar, span: Span) -> Scalar {
         use self::Scalar::*;
-        if let (&Num(n), &Num(m)) = (&self, &b) {
-            Num(n + m)
+        if let (&Num(n, _), &Num(m, _)) = (&self, &b) {
+            Num(n + m, span)
         } else {
-            Add(Box::new(self), Box::new(b))
+            Add(Box::new(self), Box::new(b), span)
         }
     }
 
-    pub fn sub(self, b: Scalar) -> Scalar {
-        self.add(b.neg())
+    pub fn sub(self, b: Scalar, span: Span) -> Scalar {
+        let neg = b.span();
+        self.add(b.neg(neg), span)
     }
 
-    pub fn neg(self) -> Scalar {
+    pub fn neg(self, span: Span) -> Scalar {
         use self::Scalar::*;
         match self {
-            Neg(a) => *a,
-            Num(a) => Num(-a),
-            _ => Neg(Box::new(self)),
+            Neg(a, _) => *a,
+            Num(a, _) => Num(-a, span),
+            _ => Neg(Box::new(self), span),
         }
     }
 
-    pub fn mul(self, b: Scalar) -> Scalar {
+    pub fn mul(self, b: Scalar, span: Span) -> Scalar {
         use self::Scalar::*;
-        if let (&Num(n), &Num(m)) = (&self, &b) {
-            Num(n * m)
+        if let (&Num(n, _), &Num(m, _)) = (&self, &b) {
+            Num(n * m, span)
         } else {
-            Mul(Box::new(self), Box::new(b))
+            Mul(Box::new(self), Box::new(b), span)
         }
     }
 
-    pub fn div(self, b: Scalar) -> Scalar {
+    pub fn div(self, b: Scalar, span: Span) -> Scalar {
         use self::Scalar::*;
-        if let (&Num(n), &Num(m)) = (&self, &b) {
-            Num(n / m)
+        if let (&Num(n, _), &Num(m, _)) = (&self, &b) {
+            Num(n / m, span)
         } else {
-            Div(Box::new(self), Box::new(b))
+            Div(Box::new(self), Box::new(b), span)
         }
     }
 }
 
 #[derive(Clone, Debug)]
 pub enum Point {
-    Add(Box<Point>, Box<Point>),
-    Neg(Box<Point>),
-    Mul(Box<Point>, Scalar),
-    Div(Box<Point>, Scalar),
-    Pair(Scalar, Scalar),
-    Intersection(Box<Line>, Box<Line>),
-    Ident(PIdent),
-    Macro(PIdent, Vec<Expr>),
+    Add(Box<Point>, Box<Point>, Span),
+    Neg(Box<Point>, Span),
+    Mul(Box<Point>, Scalar, Span),
+    Div(Box<Point>, Scalar, Span),
+    Pair(Scalar, Scalar, Span),
+    Intersection(Box<Line>, Box<Line>, Span),
+    If(Scalar, Box<Point>, Box<Point>, Span),
+    Ident(PIdent, Span),
+    Macro(PIdent, Vec<Arg>, Span),
+    Call(String, Vec<Expr>, Span),
 }
 
 impl Eval for Point {
@@ -219,66 +354,116 @@ impl Eval for Point {
     fn eval(&self, vars: &Variables) -> Result<math::Point, Box<Error>> {
         use self::Point::*;
         match *self {
-            Add(ref a, ref b) => Ok(a.eval(vars)? + b.eval(vars)?),
-            Neg(ref a) => Ok(-a.eval(vars)?),
-            Mul(ref a, ref n) => Ok(a.eval(vars)? * n.eval(vars)?),
-            Div(ref a, ref n) => Ok(a.eval(vars)? / n.eval(vars)?),
-            Pair(ref x, ref y) => Ok(math::Point(x.eval(vars)?, y.eval(vars)?)),
-            Intersection(ref a, ref b) => Ok(a.eval(vars)?.intersect(b.eval(vars)?)),
-            Ident(ref id) => Ok(*vars.get_point(id)?),
-            Macro(ref id, ref args) => vars.get_point_macro(id)?.expand(args, vars),
+            Add(ref a, ref b, _) => Ok(a.eval(vars)? + b.eval(vars)?),
+            Neg(ref a, _) => Ok(-a.eval(vars)?),
+            Mul(ref a, ref n, _) => Ok(a.eval(vars)? * n.eval(vars)?),
+            Div(ref a, ref n, _) => Ok(a.eval(vars)? / n.eval(vars)?),
+            Pair(ref x, ref y, _) => Ok(math::Point(x.eval(vars)?, y.eval(vars)?)),
+            Intersection(ref a, ref b, span) => {
+                let (la, lb) = (a.eval(vars)?, b.eval(vars)?);
+                la.intersect(lb).ok_or_else(|| {
+                    errors::Error::parallel(&a.describe(la), &b.describe(lb)).at(span).into()
+                })
+            },
+            If(ref c, ref a, ref b, _) => {
+                if c.eval(vars)? != 0.0 {a.eval(vars)} else {b.eval(vars)}
+            },
+            Ident(ref id, span) => {
+                Ok(*vars.get_point(id).map_err(|e| errors::locate(e, span))?)
+            },
+            Macro(ref id, ref args, span) => {
+                vars.get_point_macro(id)
+                    .and_then(|(m, env)| m.expand(args, span, vars, env))
+                    .map_err(|e| errors::locate(e, span))
+            },
+            Call(ref name, ref args, span) => {
+                match functions::call(name, args, vars).map_err(|e| errors::locate(e, span))? {
+                    math::Expr::Point(p) => Ok(p),
+                    val => Err(errors::Error::function_type(name, val.type_name(), "point").at(span))?,
+                }
+            },
         }
     }
 }
 
 impl Point {
-    pub fn add(self, b: Point) -> Point {
+    pub fn span(&self) -> Span {
+        use self::Point::*;
+        match *self {
+            Add(.., span) | Neg(.., span) | Mul(.., span) | Div(.., span) | Pair(.., span) |
+            Intersection(.., span) | If(.., span) | Ident(.., span) | Macro(.., span) |
+            Call(.., span) => span,
+        }
+    }
+
+    pub fn add(self, b: Point, span: Span) -> Point {
         use self::Point::*;
         let ab = (self, b);
-        if let (Pair(xa, ya), Pair(xb, yb)) = ab {
-            Pair(Scalar::add(xa, xb), Scalar::add(ya, yb))
+        if let (Pair(xa, ya, _), Pair(xb, yb, _)) = ab {
+            Pair(Scalar::add(xa, xb, span), Scalar::add(ya, yb, span), span)
         } else {
-            Add(Box::new(ab.0), Box::new(ab.1))
+            Add(Box::new(ab.0), Box::new(ab.1), span)
         }
     }
 
-    pub fn sub(self, b: Point) -> Point {
-        Point::add(self, b.neg())
+    pub fn sub(self, b: Point, span: Span) -> Point {
+        let neg = b.span();
+        Point::add(self, b.neg(neg), span)
     }
 
-    pub fn neg(self) -> Point {
+    pub fn neg(self, span: Span) -> Point {
         use self::Point::*;
         match self {
-            Neg(a) => *a,
-            Mul(a, n) => Mul(a, n.neg()),
-            Div(a, n) => Div(a, n.neg()),
-            Pair(x, y) => Pair(x.neg(), y.neg()),
-            _ => Neg(Box::new(self)),
+            Neg(a, _) => *a,
+            Mul(a, n, _) => {
+                let neg = n.span();
+                Mul(a, n.neg(neg), span)
+            },
+            Div(a, n, _) => {
+                let neg = n.span();
+                Div(a, n.neg(neg), span)
+            },
+            Pair(x, y, _) => {
+                let (nx, ny) = (x.span(), y.span());
+                Pair(x.neg(nx), y.neg(ny), span)
+            },
+            _ => Neg(Box::new(self), span),
         }
     }
 
-    pub fn mul(self, b: Scalar) -> Point {
-        Point::Mul(Box::new(self), b)
+    pub fn mul(self, b: Scalar, span: Span) -> Point {
+        Point::Mul(Box::new(self), b, span)
     }
 
-    pub fn div(self, b: Scalar) -> Point {
-        Point::Div(Box::new(self), b)
+    pub fn div(self, b: Scalar, span: Span) -> Point {
+        Point::Div(Box::new(self), b, span)
     }
 
-    pub fn intersection(a: Line, b: Line) -> Point {
-        Point::Intersection(Box::new(a), Box::new(b))
+    pub fn intersection(a: Line, b: Line, span: Span) -> Point {
+        Point::Intersection(Box::new(a), Box::new(b), span)
+    }
+
+    pub fn cond(c: Scalar, a: Point, b: Point, span: Span) -> Point {
+        match c {
+            Scalar::Num(n, _) => if n != 0.0 {a} else {b},
+            _ => Point::If(c, Box::new(a), Box::new(b), span),
+        }
     }
 }
 
 #[derive(Clone, Debug)]
 pub enum Line {
-    Add(Box<Line>, Point),
-    Parallel(Box<Line>, Point),
-    Perpendicular(Box<Line>, Point),
-    Offset(Box<Line>, Scalar),
-    Vector(Point, Point),
-    Ident(LIdent),
-    Macro(LIdent, Vec<Expr>),
+    Add(Box<Line>, Point, Span),
+    Parallel(Box<Line>, Point, Span),
+    Perpendicular(Box<Line>, Point, Span),
+    Offset(Box<Line>, Scalar, Span),
+    Vector(Point, Point, Span),
+    /// A line through a point at an angle in degrees.
+    Angle(Point, Scalar, Span),
+    If(Scalar, Box<Line>, Box<Line>, Span),
+    Ident(LIdent, Span),
+    Macro(LIdent, Vec<Arg>, Span),
+    Call(String, Vec<Expr>, Span),
 }
 
 impl Eval for Line {
@@ -289,119 +474,261 @@ impl Eval for Line {
     fn eval(&self, vars: &Variables) -> Result<math::Line, Box<Error>> {
         use self::Line::*;
         match *self {
-            Add(ref l, ref p) => Ok(l.eval(vars)? + p.eval(vars)?),
-            Parallel(ref l, ref p) => Ok(l.eval(vars)?.parallel(p.eval(vars)?)),
-            Perpendicular(ref l, ref p) => Ok(l.eval(vars)?.perpendicular(p.eval(vars)?)),
-            Offset(ref l, ref s) => Ok(l.eval(vars)?.offset(s.eval(vars)?)),
-            Vector(ref o, ref v) => Ok(math::Line {
-                origin: o.eval(vars)?,
-                vector: v.eval(vars)?,
-            }),
-            Ident(ref id) => Ok(*vars.get_line(id)?),
-            Macro(ref id, ref args) => vars.get_line_macro(id)?.expand(args, vars),
+            Add(ref l, ref p, _) => Ok(l.eval(vars)? + p.eval(vars)?),
+            Parallel(ref l, ref p, _) => Ok(l.eval(vars)?.parallel(p.eval(vars)?)),
+            Perpendicular(ref l, ref p, _) => Ok(l.eval(vars)?.perpendicular(p.eval(vars)?)),
+            Offset(ref l, ref s, _) => Ok(l.eval(vars)?.offset(s.eval(vars)?)),
+            Vector(ref o, ref v, span) => {
+                let line = math::Line {
+                    origin: o.eval(vars)?,
+                    vector: v.eval(vars)?,
+                };
+                if line.vector.unit().is_none() {
+                    Err(errors::Error::geometry(
+                            format!("Line {} has no direction", line).as_ref()).at(span))?;
+                }
+                Ok(line)
+            },
+            Angle(ref p, ref theta, span) => {
+                let vector = math::Point::polar(1.0, theta.eval(vars)?.to_radians());
+                Ok(math::Line {
+                    origin: p.eval(vars)?,
+                    vector: errors::finite(vector).map_err(|e| e.at(span))?,
+                })
+            },
+            If(ref c, ref a, ref b, _) => {
+                if c.eval(vars)? != 0.0 {a.eval(vars)} else {b.eval(vars)}
+            },
+            Ident(ref id, span) => {
+                Ok(*vars.get_line(id).map_err(|e| errors::locate(e, span))?)
+            },
+            Macro(ref id, ref args, span) => {
+                vars.get_line_macro(id)
+                    .and_then(|(m, env)| m.expand(args, span, vars, env))
+                    .map_err(|e| errors::locate(e, span))
+            },
+            Call(ref name, ref args, span) => {
+                match functions::call(name, args, vars).map_err(|e| errors::locate(e, span))? {
+                    math::Expr::Line(l) => Ok(l),
+                    val => Err(errors::Error::function_type(name, val.type_name(), "line").at(span))?,
+                }
+            },
         }
     }
 }
 
 impl Line {
-    pub fn add(self, b: Point) -> Line {
+    pub fn span(&self) -> Span {
+        use self::Line::*;
+        match *self {
+            Add(.., span) | Parallel(.., span) | Perpendicular(.., span) | Offset(.., span) |
+            Vector(.., span) | Angle(.., span) | If(.., span) | Ident(.., span) |
+            Macro(.., span) | Call(.., span) => span,
+        }
+    }
+
+    pub fn add(self, b: Point, span: Span) -> Line {
         use self::Line::*;
         match self {
-            Add(l, p) => Add(l, p.add(b)),
-            Parallel(l, p) => Parallel(l, p.add(b)),
-            Perpendicular(l, p) => Perpendicular(l, p.add(b)),
-            Vector(o, v) => Vector(o.add(b), v),
-            _ => Add(Box::new(self), b),
+            Add(l, p, _) => Add(l, p.add(b, span), span),
+            Parallel(l, p, _) => Parallel(l, p.add(b, span), span),
+            Perpendicular(l, p, _) => Perpendicular(l, p.add(b, span), span),
+            Vector(o, v, _) => Vector(o.add(b, span), v, span),
+            _ => Add(Box::new(self), b, span),
         }
     }
 
-    pub fn sub(self, b: Point) -> Line {
+    pub fn sub(self, b: Point, span: Span) -> Line {
         use self::Line::*;
         match self {
-            Add(l, p) => Add(l, p.sub(b)),
-            Parallel(l, p) => Parallel(l, p.sub(b)),
-            Perpendicular(l, p) => Perpendicular(l, p.sub(b)),
-            Vector(o, v) => Vector(o.sub(b), v),
-            _ => Add(Box::new(self), b.neg()),
+            Add(l, p, _) => Add(l, p.sub(b, span), span),
+            Parallel(l, p, _) => Parallel(l, p.sub(b, span), span),
+            Perpendicular(l, p, _) => Perpendicular(l, p.sub(b, span), span),
+            Vector(o, v, _) => Vector(o.sub(b, span), v, span),
+            _ => {
+                let neg = b.span();
+                Add(Box::new(self), b.neg(neg), span)
+            },
         }
     }
 
-    pub fn parallel(self, b: Point) -> Line {
+    pub fn parallel(self, b: Point, span: Span) -> Line {
         use self::Line::*;
         match self {
-            Add(l, _) => Parallel(l, b),
-            Parallel(l, _) => Parallel(l, b),
-            Perpendicular(l, _) => Perpendicular(l, b),
-            Vector(_, v) => Vector(b, v),
-            _ => Parallel(Box::new(self), b),
+            Add(l, _, _) => Parallel(l, b, span),
+            Parallel(l, _, _) => Parallel(l, b, span),
+            Perpendicular(l, _, _) => Perpendicular(l, b, span),
+            Vector(_, v, _) => Vector(b, v, span),
+            _ => Parallel(Box::new(self), b, span),
         }
     }
 
-    pub fn perpendicular(self, b: Point) -> Line {
+    pub fn perpendicular(self, b: Point, span: Span) -> Line {
         use self::Line::*;
         match self {
-            Add(l, _) => Perpendicular(l, b),
-            Parallel(l, _) => Perpendicular(l, b),
-            Perpendicular(l, _) => Parallel(l, b),
-            _ => Perpendicular(Box::new(self), b),
+            Add(l, _, _) => Perpendicular(l, b, span),
+            Parallel(l, _, _) => Perpendicular(l, b, span),
+            Perpendicular(l, _, _) => Parallel(l, b, span),
+            _ => Perpendicular(Box::new(self), b, span),
+        }
+    }
+
+    pub fn offset(self, b: Scalar, span: Span) -> Line {
+        Line::Offset(Box::new(self), b, span)
+    }
+
+    /// Describes the line in error messages, by name if it has one.
+    fn describe(&self, val: math::Line) -> String {
+        match *self {
+            Line::Ident(ref id, _) => format!("{}", id),
+            Line::Macro(ref id, _, _) => format!("{}[...] = {}", id, val),
+            _ => format!("{}", val),
         }
     }
 
-    pub fn offset(self, b: Scalar) -> Line {
-        Line::Offset(Box::new(self), b)
+    pub fn vector(a: Point, b: Point, span: Span) -> Line {
+        Line::Vector(a, b, span)
     }
 
-    pub fn vector(a: Point, b: Point) -> Line {
-        Line::Vector(a, b)
+    pub fn angle(p: Point, theta: Scalar, span: Span) -> Line {
+        Line::Angle(p, theta, span)
     }
 
-    pub fn between(a: Point, b: Point) -> Line {
-        Line::Vector(a.clone(), b.sub(a))
+    pub fn cond(c: Scalar, a: Line, b: Line, span: Span) -> Line {
+        match c {
+            Scalar::Num(n, _) => if n != 0.0 {a} else {b},
+            _ => Line::If(c, Box::new(a), Box::new(b), span),
+        }
+    }
+
+    pub fn between(a: Point, b: Point, span: Span) -> Line {
+        Line::Vector(a.clone(), b.sub(a, span), span)
     }
 }
 
 #[derive(Clone, Debug)]
-pub struct Route {
-    segments: Vec<Segment>,
-    offsets: Vec<Scalar>,
+pub enum Str {
+    /// A string literal, in which `{$x}` and `{#s}` are replaced by the values
+    /// of `$x` and `#s`.
+    Lit(String, Span),
+    Concat(Box<Str>, Box<Str>, Span),
+    Ident(StrIdent, Span),
+    Macro(StrIdent, Vec<Arg>, Span),
 }
 
-impl Route {
-    pub fn start(start: Point, offset: Option<Scalar>, end: Point) -> Route {
-        let seg = Segment { start, end };
-        Route {
-            segments: vec![seg],
-            offsets: vec![offset.unwrap_or(Scalar::Num(0.0))]
+impl Eval for Str {
+    type Output = String;
+
+    type Ident = StrIdent;
+
+    fn eval(&self, vars: &Variables) -> Result<String, Box<Error>> {
+        use self::Str::*;
+        match *self {
+            Lit(ref s, span) => format_str(s, vars).map_err(|e| errors::locate(e, span)),
+            Concat(ref a, ref b, _) => Ok(a.eval(vars)? + b.eval(vars)?.as_ref()),
+            Ident(ref id, span) => {
+                Ok(vars.get_string(id).map_err(|e| errors::locate(e, span))?.clone())
+            },
+            Macro(ref id, ref args, span) => {
+                vars.get_string_macro(id)
+                    .and_then(|(m, env)| m.expand(args, span, vars, env))
+                    .map_err(|e| errors::locate(e, span))
+            },
         }
     }
+}
 
-    pub fn concat(mut self, b: Route) -> Route {
-        let mut b = b;
-        self.segments.append(&mut b.segments);
-        self.offsets.append(&mut b.offsets);
-        self
+impl Str {
+    pub fn concat(self, b: Str, span: Span) -> Str {
+        Str::Concat(Box::new(self), Box::new(b), span)
     }
+}
 
-    pub fn extend(mut self, offset: Option<Scalar>, end: Point) -> Route {
-        let offset = offset.unwrap_or(Scalar::Num(0.0));
-        let seg = Segment {
-            start: self.segments.last().unwrap().clone().end,
-            end,
-        };
-        self.segments.push(seg);
-        self.offsets.push(offset);
-        self
+/// Substitutes the values of scalars and strings into a string literal.
+fn format_str(s: &str, vars: &Variables) -> Result<String, Box<Error>> {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\{([$#](?:\w+::)*\w+)\}").unwrap();
     }
+    let mut out = String::new();
+    let mut last = 0;
+    for caps in RE.captures_iter(s) {
+        let (all, var) = (caps.get(0).unwrap(), caps.get(1).unwrap().as_str());
+        out.push_str(&s[last..all.start()]);
+        if var.starts_with('$') {
+            out.push_str(&format!("{}", vars.get_scalar(&SIdent::from_str(var)?)?));
+        } else {
+            out.push_str(vars.get_string(&StrIdent::from_str(var)?)?);
+        }
+        last = all.end();
+    }
+    out.push_str(&s[last..]);
+    Ok(out)
+}
+
+#[derive(Clone, Debug)]
+pub enum Route {
+    Start(Segment, Option<Scalar>, Span),
+    Extend(Box<Route>, Option<Scalar>, Point, Span),
+    Concat(Box<Route>, Box<Route>, Span),
+    Macro(RIdent, Vec<Arg>, Span),
+}
 
-    fn eval(self, vars: &mut Variables) -> Result<route::Route, Box<Error>> {
-        let mut r = route::Route::new();
-        for (seg, off) in self.segments.iter().zip(self.offsets.iter()) {
-            let seg = seg.eval(vars)?;
-            let off = off.eval(vars)?;
-            vars.insert_segment(seg, off);
-            r.push(seg, off);
+impl Eval for Route {
+    type Output = route::Route;
+
+    type Ident = RIdent;
+
+    fn eval(&self, vars: &Variables) -> Result<route::Route, Box<Error>> {
+        use self::Route::*;
+        match *self {
+            Start(ref seg, ref off, _) => {
+                let mut r = route::Route::new();
+                r.push(seg.eval(vars)?, eval_offset(off, vars)?);
+                Ok(r)
+            },
+            Extend(ref r, ref off, ref end, span) => {
+                let mut r = r.eval(vars)?;
+                let start = r.segments().last().unwrap().end;
+                let seg = segment(start, end.eval(vars)?).map_err(|e| errors::locate(e, span))?;
+                r.push(seg, eval_offset(off, vars)?);
+                Ok(r)
+            },
+            Concat(ref a, ref b, _) => {
+                let mut a = a.eval(vars)?;
+                let b = b.eval(vars)?;
+                for (i, &seg) in b.segments().iter().enumerate() {
+                    a.push(seg, b.offset(i));
+                }
+                Ok(a)
+            },
+            Macro(ref id, ref args, span) => {
+                vars.get_route_macro(id)
+                    .and_then(|(m, env)| m.expand(args, span, vars, env))
+                    .map_err(|e| errors::locate(e, span))
+            },
         }
-        Ok(r)
+    }
+}
+
+impl Route {
+    pub fn start(start: Point, offset: Option<Scalar>, end: Point, span: Span) -> Route {
+        Route::Start(Segment { start, end, span }, offset, span)
+    }
+
+    pub fn concat(self, b: Route, span: Span) -> Route {
+        Route::Concat(Box::new(self), Box::new(b), span)
+    }
+
+    pub fn extend(self, offset: Option<Scalar>, end: Point, span: Span) -> Route {
+        Route::Extend(Box::new(self), offset, end, span)
+    }
+}
+
+fn eval_offset(off: &Option<Scalar>,
+               vars: &Variables) -> Result<Option<math::Scalar>, Box<Error>> {
+    match *off {
+        Some(ref off) => Ok(Some(off.eval(vars)?)),
+        None => Ok(None),
     }
 }
 
@@ -409,55 +736,72 @@ impl Route {
 pub struct Segment {
     pub start: Point,
     pub end: Point,
+    pub span: Span,
 }
 
 impl Segment {
     fn eval(&self, vars: &Variables) -> Result<route::Segment, Box<Error>> {
-        Ok(route::Segment {
-            start: self.start.eval(vars)?,
-            end: self.end.eval(vars)?,
-        })
+        segment(self.start.eval(vars)?, self.end.eval(vars)?)
+            .map_err(|e| errors::locate(e, self.span))
     }
 
     fn line(self) -> Line {
-        Line::between(self.start, self.end)
+        Line::between(self.start, self.end, self.span)
+    }
+}
+
+fn segment(start: math::Point, end: math::Point) -> Result<route::Segment, Box<Error>> {
+    if start == end {
+        Err(errors::Error::geometry(
+                format!("Segment from ({}) to itself has zero length", start).as_ref()))?;
     }
+    Ok(route::Segment { start, end })
 }
 
 #[derive(Clone, Debug)]
 pub enum Stop {
-    Line(Segment, Line, LabelPos, String),
-    Segment(Segment, Segment, LabelPos, LabelPos, String),
+    Line(Segment, Line, LabelPos, Span),
+    Segment(Segment, Segment, LabelPos, LabelPos, Span),
+    Macro(StIdent, Vec<Arg>, Span),
 }
 
-impl Stop {
-    pub fn eval(&self, vars: &Variables) -> Result<stop::Stop, Box<Error>> {
+impl Eval for Stop {
+    type Output = stop::Stop;
+
+    type Ident = StIdent;
+
+    fn eval(&self, vars: &Variables) -> Result<stop::Stop, Box<Error>> {
         use self::Stop::*;
         match *self {
-            Line(ref seg, ref line, lp, ref label) => {
+            Line(ref seg, ref line, lp, _) => {
                 Ok(stop::Stop::Line(
                         seg.eval(vars)?,
                         line.eval(vars)?,
-                        lp,
-                        label.clone()))
+                        lp))
             },
-            Segment(ref a, ref b, pos_a, pos_b, ref label) => {
+            Segment(ref a, ref b, pos_a, pos_b, _) => {
                 Ok(stop::Stop::Segment(
                         a.eval(vars)?,
                         b.eval(vars)?,
                         pos_a,
-                        pos_b,
-                        label.clone()))
+                        pos_b))
+            },
+            Macro(ref id, ref args, span) => {
+                vars.get_stop_macro(id)
+                    .and_then(|(m, env)| m.expand(args, span, vars, env))
+                    .map_err(|e| errors::locate(e, span))
             },
         }
     }
+}
 
+impl Stop {
     pub fn perpendicular(
         seg: Segment,
         p: Point,
         pos: LabelPos,
-        label: String) -> Stop {
-        Stop::Line(seg.clone(), seg.line().perpendicular(p), pos, label)
+        span: Span) -> Stop {
+        Stop::Line(seg.clone(), seg.line().perpendicular(p, span), pos, span)
     }
 }
 
@@ -467,74 +811,270 @@ pub enum LabelPos {
     Minus,
 }
 
+/// A macro parameter, along with the value it takes when no argument is
+/// given for it.
+#[derive(Clone, Debug)]
+pub struct Param {
+    pub id: Ident,
+    /// For a parameter taking a macro, the parameters that macro must have.
+    pub sig: Option<Vec<Ident>>,
+    pub default: Option<Expr>,
+}
+
+impl Param {
+    /// Formats a macro parameter as it is declared, such as `$f[$x, @p]`.
+    fn signature<T: fmt::Display>(id: &T, params: &[&Ident]) -> String {
+        let params = params.iter().map(|id| format!("{}", id)).collect::<Vec<_>>();
+        format!("{}[{}]", id, params.join(", "))
+    }
+}
+
+/// An argument in a macro call, given either by position or by the name of
+/// its parameter.
+#[derive(Clone, Debug)]
+pub enum Arg {
+    Positional(Expr),
+    Named(Ident, Expr),
+}
+
 #[derive(Clone, Debug)]
 pub struct Macro<T: Eval> {
     id: T::Ident,
-    args: Vec<Ident>,
+    params: Vec<Param>,
     body: T,
 }
 
 impl<T: Eval> Macro<T> {
-    pub fn expand(&self,
-                  args: &Vec<Expr>,
-                  vars: &Variables) -> Result<T::Output, Box<Error>> {
-        let mut locals = Variables::with_globals(vars);
-        if args.len() != self.args.len() {
-            Err(errors::Error::macro_args(
-                    format!("{}", self.id).as_ref(),
-                    args.len(),
-                    self.args.len()))?;
-        }
-        for (id, val) in self.args.iter().zip(args.iter()) {
-            match *id {
-                Ident::Scalar(ref id) => {
-                    if let Expr::Scalar(ref val) = *val {
-                        locals.insert_scalar(id.clone(), val)?;
-                    } else {
-                        Err(errors::Error::macro_arg_type(
-                                format!("{}", self.id).as_ref(),
-                                format!("{}", id).as_ref(),
-                                "Scalar",
-                                format!("{:?}", val).as_ref()))?;
+    /// Evaluates the arguments in `vars`, and the body in a new scope
+    /// enclosed by `scope`. Parameters without an argument take their default
+    /// values, which are evaluated in the new scope after the parameters
+    /// before them are bound. `span` is the location of the call.
+    ///
+    /// A macro passed as an argument is expanded in the scope it was passed
+    /// from, not in the scope of the macro it is passed to.
+    pub fn expand<'s>(&self,
+                      args: &[Arg],
+                      span: Span,
+                      vars: &'s Variables<'s>,
+                      scope: &'s Variables<'s>) -> Result<T::Output, Box<Error>> {
+        let name = format!("{}", self.id);
+        let names = self.params.iter().map(|p| format!("{}", p.id)).collect::<Vec<_>>();
+        let positional = args.iter().filter(|a| match **a {
+            Arg::Positional(_) => true,
+            Arg::Named(..) => false,
+        }).count();
+        if positional > self.params.len() {
+            Err(errors::Error::macro_args(name.as_ref(), positional, self.params.len()))?;
+        }
+        let mut given = vec![None; self.params.len()];
+        let mut next = 0;
+        for arg in args {
+            let (i, val) = match *arg {
+                Arg::Positional(ref val) => {
+                    next += 1;
+                    (next - 1, val)
+                },
+                Arg::Named(ref id, ref val) => {
+                    let id = format!("{}", id);
+                    match names.iter().position(|n| *n == id) {
+                        Some(i) => (i, val),
+                        None => Err(errors::Error::unknown_arg(name.as_ref(), id.as_ref()))?,
                     }
+                },
+            };
+            if given[i].is_some() {
+                Err(errors::Error::duplicate_arg(name.as_ref(), names[i].as_ref()))?;
+            }
+            given[i] = Some(val);
+        }
+        if vars.stack_exhausted() {
+            Err(errors::Error::stack_limit(name.as_ref(), vars.depth()))?;
+        }
+        let mut locals = match Variables::nested(scope, vars) {
+            Some(locals) => locals,
+            None => Err(errors::Error::recursion_limit(name.as_ref(), vars.max_depth))?,
+        };
+        // arguments are evaluated in a scope nested as deeply as the body, so
+        // that macros called by them count towards the depth while this
+        // expansion is in progress; like `locals`, it is within `max_depth`
+        let within = Variables::nested(vars, vars).unwrap();
+        // the macros bound to earlier parameters, which defaults can name
+        let mut passed = Vec::new();
+        for (param, val) in self.params.iter().zip(given) {
+            let (val, is_given) = match (val, &param.default) {
+                (Some(val), _) => (val, true),
+                (None, Some(default)) => (default, false),
+                (None, None) => {
+                    let id = format!("{}", param.id);
+                    Err(errors::Error::missing_arg(name.as_ref(), id.as_ref()))?
+                },
+            };
+            let bound = match param.sig {
+                Some(ref sig) if is_given => self.macro_arg(param, sig, val, vars, &[])?,
+                // a default is written in the definition, so it names a macro
+                // from the definition's scope
+                Some(ref sig) => self.macro_arg(param, sig, val, scope, &passed)?,
+                None => {
+                    self.check_arg(&param.id, val)?;
+                    Bound::Value(val.eval(if is_given { &within } else { &locals })?)
+                },
+            };
+            let id = format!("{}", param.id);
+            match (&param.id, bound) {
+                (Ident::Scalar(id), Bound::Value(math::Expr::Scalar(x))) => {
+                    locals.bind_scalar(id.clone(), Some(x));
+                },
+                (Ident::Point(id), Bound::Value(math::Expr::Point(p))) => {
+                    locals.bind_point(id.clone(), Some(p));
+                },
+                (Ident::Line(id), Bound::Value(math::Expr::Line(l))) => {
+                    locals.bind_line(id.clone(), Some(l));
+                },
+                (Ident::Scalar(f), Bound::Scalar(m, env)) => {
+                    passed.push((id.clone(), Bound::Scalar(m.clone(), env)));
+                    locals.bind_scalar_macro(f.clone(), Some(m));
+                    locals.bind_closure(id, env);
+                },
+                (Ident::Point(f), Bound::Point(m, env)) => {
+                    passed.push((id.clone(), Bound::Point(m.clone(), env)));
+                    locals.bind_point_macro(f.clone(), Some(m));
+                    locals.bind_closure(id, env);
+                },
+                (Ident::Line(f), Bound::Line(m, env)) => {
+                    passed.push((id.clone(), Bound::Line(m.clone(), env)));
+                    locals.bind_line_macro(f.clone(), Some(m));
+                    locals.bind_closure(id, env);
+                },
+                _ => unreachable!(),
+            }
+        }
+        self.body.eval(&locals).map_err(|e| errors::expansion(e, name.as_ref(), span))
+    }
+
+    /// Looks up the macro named by the argument `val` to a macro parameter,
+    /// among the macros bound to earlier parameters in `passed` and then in
+    /// `vars`, and checks that it takes the parameters in the signature `sig`.
+    fn macro_arg<'s>(&self,
+                     param: &Param,
+                     sig: &[Ident],
+                     val: &Expr,
+                     vars: &'s Variables<'s>,
+                     passed: &[(String, Bound<'s>)]) -> Result<Bound<'s>, Box<Error>> {
+        let earlier = |f: String| {
+            passed.iter().find(|&(id, _)| *id == f).map(|(_, bound)| bound.clone())
+        };
+        let bound = match (&param.id, val) {
+            (&Ident::Scalar(_), &Expr::Scalar(Scalar::Ident(ref f, _))) => {
+                match earlier(format!("{}", f)) {
+                    Some(bound) => bound,
+                    None => {
+                        let (m, env) = vars.get_scalar_macro(f)?;
+                        Bound::Scalar(m.clone(), env)
+                    },
                 }
-                Ident::Point(ref id) => {
-                    if let Expr::Point(ref val) = *val {
-                        locals.insert_point(id.clone(), val)?;
-                    } else {
-                        Err(errors::Error::macro_arg_type(
-                                format!("{}", self.id).as_ref(),
-                                format!("{}", id).as_ref(),
-                                "Point",
-                                format!("{:?}", val).as_ref()))?;
-                    }
+            },
+            (&Ident::Point(_), &Expr::Point(Point::Ident(ref f, _))) => {
+                match earlier(format!("{}", f)) {
+                    Some(bound) => bound,
+                    None => {
+                        let (m, env) = vars.get_point_macro(f)?;
+                        Bound::Point(m.clone(), env)
+                    },
                 }
-                Ident::Line(ref id) => {
-                    if let Expr::Line(ref val) = *val {
-                        locals.insert_line(id.clone(), val)?;
-                    } else {
-                        Err(errors::Error::macro_arg_type(
-                                format!("{}", self.id).as_ref(),
-                                format!("{}", id).as_ref(),
-                                "Line",
-                                format!("{:?}", val).as_ref()))?;
-                    }
+            },
+            (&Ident::Line(_), &Expr::Line(Line::Ident(ref f, _))) => {
+                match earlier(format!("{}", f)) {
+                    Some(bound) => bound,
+                    None => {
+                        let (m, env) = vars.get_line_macro(f)?;
+                        Bound::Line(m.clone(), env)
+                    },
                 }
+            },
+            (id, _) => {
+                let type_ = match *id {
+                    Ident::Scalar(_) => "Scalar macro",
+                    Ident::Point(_) => "Point macro",
+                    Ident::Line(_) => "Line macro",
+                };
+                Err(errors::Error::macro_arg_type(
+                        format!("{}", self.id).as_ref(),
+                        format!("{}", param.id).as_ref(),
+                        type_,
+                        val.type_name()).at(val.span()))?
+            },
+        };
+        {
+            let (id, params) = match bound {
+                Bound::Scalar(ref m, _) => (format!("{}", m.id), &m.params),
+                Bound::Point(ref m, _) => (format!("{}", m.id), &m.params),
+                Bound::Line(ref m, _) => (format!("{}", m.id), &m.params),
+                Bound::Value(_) => return Ok(bound),
+            };
+            let matches = sig.len() <= params.len() &&
+                sig.iter().zip(params).all(|(a, p)| p.sig.is_none() && same_type(a, &p.id)) &&
+                params[sig.len()..].iter().all(|p| p.default.is_some());
+            if !matches {
+                let expected = Param::signature(&param.id, &sig.iter().collect::<Vec<_>>());
+                let got = Param::signature(&id, &params.iter().map(|p| &p.id).collect::<Vec<_>>());
+                Err(errors::Error::macro_signature(
+                        format!("{}", self.id).as_ref(),
+                        format!("{}", param.id).as_ref(),
+                        expected.as_ref(),
+                        got.as_ref()).at(val.span()))?;
             }
         }
-        self.body.eval(&locals)
+        Ok(bound)
+    }
+
+    /// Checks that an argument has the type of the parameter `id`.
+    fn check_arg(&self, id: &Ident, val: &Expr) -> Result<(), Box<Error>> {
+        let type_ = match (id, val) {
+            (&Ident::Scalar(_), &Expr::Scalar(_)) |
+            (&Ident::Point(_), &Expr::Point(_)) |
+            (&Ident::Line(_), &Expr::Line(_)) => return Ok(()),
+            (&Ident::Scalar(_), _) => "Scalar",
+            (&Ident::Point(_), _) => "Point",
+            (&Ident::Line(_), _) => "Line",
+        };
+        Err(errors::Error::macro_arg_type(
+                format!("{}", self.id).as_ref(),
+                format!("{}", id).as_ref(),
+                type_,
+                val.type_name()).at(val.span()))?
     }
 }
 
+/// The value bound to a macro parameter. Macros are bound along with the
+/// scope to expand them in.
+#[derive(Clone)]
+enum Bound<'s> {
+    Value(math::Expr),
+    Scalar(Macro<Scalar>, &'s Variables<'s>),
+    Point(Macro<Point>, &'s Variables<'s>),
+    Line(Macro<Line>, &'s Variables<'s>),
+}
+
+fn same_type(a: &Ident, b: &Ident) -> bool {
+    matches!((a, b),
+             (&Ident::Scalar(_), &Ident::Scalar(_)) |
+             (&Ident::Point(_), &Ident::Point(_)) |
+             (&Ident::Line(_), &Ident::Line(_)))
+}
+
 #[derive(Clone, Debug)]
 pub enum Definition {
     Scalar(SIdent, Scalar),
     Point(PIdent, Point),
     Line(LIdent, Line),
     Route(RIdent, Route),
-    ScalarMacro(SIdent, Vec<Ident>, Scalar),
-    PointMacro(PIdent, Vec<Ident>, Point),
-    LineMacro(LIdent, Vec<Ident>, Line),
+    ScalarMacro(SIdent, Vec<Param>, Scalar),
+    PointMacro(PIdent, Vec<Param>, Point),
+    LineMacro(LIdent, Vec<Param>, Line),
+    RouteMacro(RIdent, Vec<Param>, Route),
+    StopMacro(StIdent, Vec<Param>, Box<Stop>),
+    Str(StrIdent, Str),
+    StrMacro(StrIdent, Vec<Param>, Str),
 }
 
 impl Definition {
@@ -542,26 +1082,202 @@ impl Definition {
         vars.eval_def(self)?;
         Ok(())
     }
+
+    /// Evaluates the definition written at `span`, which may replace an
+    /// earlier one if `redefine` is set.
+    fn eval_at(self, vars: &mut Variables, span: Span, redefine: bool) -> Result<(), Box<Error>> {
+        let d = self.interpolate(vars).map_err(|e| errors::locate(e, span))?;
+        let id = d.ident();
+        vars.check_redefinition(&id, span, redefine).map_err(|e| errors::locate(e, span))?;
+        d.eval(vars).map_err(|e| errors::locate(e, span))?;
+        vars.record_definition(id, span);
+        Ok(())
+    }
+
+    /// Interpolates any scalars in the identifier being defined.
+    pub fn interpolate(self, vars: &Variables) -> Result<Definition, Box<Error>> {
+        use self::Definition::*;
+        Ok(match self {
+            Scalar(id, val) => Scalar(id.interpolate(vars)?, val),
+            Point(id, val) => Point(id.interpolate(vars)?, val),
+            Line(id, val) => Line(id.interpolate(vars)?, val),
+            Route(id, val) => Route(id.interpolate(vars)?, val),
+            ScalarMacro(id, args, body) => ScalarMacro(id.interpolate(vars)?, args, body),
+            PointMacro(id, args, body) => PointMacro(id.interpolate(vars)?, args, body),
+            LineMacro(id, args, body) => LineMacro(id.interpolate(vars)?, args, body),
+            RouteMacro(id, args, body) => RouteMacro(id.interpolate(vars)?, args, body),
+            StopMacro(id, args, body) => StopMacro(id.interpolate(vars)?, args, body),
+            Str(id, val) => Str(id.interpolate(vars)?, val),
+            StrMacro(id, args, body) => StrMacro(id.interpolate(vars)?, args, body),
+        })
+    }
+
+    /// Returns the identifier being defined. Macro names are suffixed with
+    /// `[]`.
+    pub fn ident(&self) -> String {
+        use self::Definition::*;
+        match *self {
+            Scalar(ref id, _) => format!("{}", id),
+            Point(ref id, _) => format!("{}", id),
+            Line(ref id, _) => format!("{}", id),
+            Route(ref id, _) => format!("{}", id),
+            ScalarMacro(ref id, _, _) => format!("{}[]", id),
+            PointMacro(ref id, _, _) => format!("{}[]", id),
+            LineMacro(ref id, _, _) => format!("{}[]", id),
+            RouteMacro(ref id, _, _) => format!("{}[]", id),
+            StopMacro(ref id, _, _) => format!("{}[]", id),
+            Str(ref id, _) => format!("{}", id),
+            StrMacro(ref id, _, _) => format!("{}[]", id),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub enum Statement {
-    Definition(Definition),
-    Setup(Setup),
-    Command(Command),
+    Definition(Definition, Span),
+    /// A definition which may replace an earlier one, marked `\redefine`.
+    Redefinition(Definition, Span),
+    Setup(Setup, Span),
+    Command(Command, Span),
+    For(SIdent, Range, Vec<Statement>, Span),
+    Include(String, Span),
+    Import(String, String, Span),
+    /// An imported file, after it has been loaded.
+    Module(String, Vec<Statement>, Span),
+    /// A statement preceded by a doc comment.
+    Doc(String, Box<Statement>),
     None,
 }
 
 impl Statement {
-    pub fn eval(self, vars: &mut Variables) -> Result<(), Box<Error>> {
+    /// Evaluates the statement, adding any errors to `errs`. Every statement
+    /// in the body of a loop is evaluated on every iteration, even if some
+    /// fail.
+    pub fn eval(self, vars: &mut Variables, errs: &mut Vec<Box<Error>>) {
         use self::Statement::*;
-        match self {
-            Definition(d) => d.eval(vars)?,
-            Setup(s) => s.eval(vars)?,
-            Command(c) => c.eval_push(vars)?,
-            None => {},
+        let res = match self {
+            Definition(d, span) => d.eval_at(vars, span, false),
+            Redefinition(d, span) => d.eval_at(vars, span, true),
+            Setup(s, span) => s.eval(vars).map_err(|e| errors::locate(e, span)),
+            Command(c, span) => c.eval_push(vars).map_err(|e| errors::locate(e, span)),
+            For(id, range, body, span) => {
+                match range.eval(vars).map_err(|e| errors::locate(e, span)) {
+                    Ok(vals) => {
+                        let old = vars.bind_scalar(id.clone(), Option::None);
+                        let mut found = Vec::new();
+                        for val in vals {
+                            vars.bind_scalar(id.clone(), Some(val));
+                            for st in body.iter().cloned() {
+                                st.eval(vars, &mut found);
+                            }
+                        }
+                        vars.bind_scalar(id, old);
+                        errs.extend(errors::dedup(found));
+                        Ok(())
+                    },
+                    Err(err) => Err(err),
+                }
+            },
+            Module(name, body, _) => {
+                let mut module = vars.module();
+                module.declare(&body);
+                for st in body {
+                    match st.check_module() {
+                        Ok(()) => st.eval(&mut module, errs),
+                        Err(err) => errs.push(err),
+                    }
+                }
+                vars.insert_module(name, module);
+                Ok(())
+            },
+            Doc(doc, st) => {
+                vars.doc = Some(doc);
+                st.eval(vars, errs);
+                vars.doc = Option::None;
+                Ok(())
+            },
+            // resolved when the program is loaded
+            Include(..) | Import(..) => Ok(()),
+            None => Ok(()),
         };
-        Ok(())
+        if let Err(err) = res {
+            errs.push(err);
+        }
+    }
+
+    /// Checks that the statement can appear in an imported file, which can
+    /// only define things.
+    fn check_module(&self) -> Result<(), Box<Error>> {
+        use self::Statement::*;
+        match *self {
+            Setup(_, span) | Command(_, span) => Err(errors::Error::module_command().at(span))?,
+            For(_, _, ref body, _) => {
+                for st in body {
+                    st.check_module()?;
+                }
+                Ok(())
+            },
+            Doc(_, ref st) => st.check_module(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the identifier defined by this statement, if any.
+    pub fn defines(&self) -> Option<String> {
+        match *self {
+            Statement::Definition(ref d, _) | Statement::Redefinition(ref d, _) => Some(d.ident()),
+            Statement::Doc(_, ref st) => st.defines(),
+            _ => Option::None,
+        }
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        use self::Statement::*;
+        match *self {
+            Definition(_, span) | Redefinition(_, span) => Some(span),
+            Setup(_, span) | Command(_, span) => Some(span),
+            For(.., span) | Include(_, span) | Import(.., span) | Module(.., span) => Some(span),
+            Doc(_, ref st) => st.span(),
+            None => Option::None,
+        }
+    }
+}
+
+/// The highest value `\max_depth` can be set to.
+pub const MAX_DEPTH: usize = 10_000;
+
+/// The stack size of the thread evaluating the map. Macro expansions which
+/// would need more stack than this are reported as errors.
+pub const STACK_SIZE: usize = 64 * 1024 * 1024;
+
+/// The maximum number of values a `\for` loop can take.
+const MAX_ITERATIONS: usize = 100_000;
+
+/// The values taken by the variable of a `\for` loop.
+#[derive(Clone, Debug)]
+pub enum Range {
+    /// Every integer step from the first value up to, but not including, the
+    /// second.
+    Between(Scalar, Scalar, Span),
+    List(Vec<Scalar>, Span),
+}
+
+impl Range {
+    fn eval(&self, vars: &Variables) -> Result<Vec<math::Scalar>, Box<Error>> {
+        match *self {
+            Range::Between(ref a, ref b, span) => {
+                let (a, b) = (a.eval(vars)?, b.eval(vars)?);
+                if !a.is_finite() || !b.is_finite() {
+                    Err(errors::Error::infinite_range(a, b).at(span))?;
+                }
+                let count = (b - a).ceil().max(0.0);
+                if count > MAX_ITERATIONS as f64 {
+                    Err(errors::Error::loop_limit(count, MAX_ITERATIONS).at(span))?;
+                }
+                Ok((0..count as usize).map(|i| a + i as f64).collect())
+            },
+            Range::List(ref v, _) => v.iter().map(|x| x.eval(vars)).collect(),
+        }
     }
 }
 
@@ -572,6 +1288,9 @@ pub enum Setup {
     Bounds(Scalar, Scalar, Scalar, Scalar),
     BoundsPoints(Point, Point),
     Style(String),
+    AutoOffsets,
+    MinimizeCrossings,
+    MaxDepth(Scalar),
 }
 
 impl Setup {
@@ -601,6 +1320,19 @@ impl Setup {
             Style(s) => {
                 vars.style.push(s);
             },
+            AutoOffsets => {
+                vars.auto_offsets = true;
+            },
+            MinimizeCrossings => {
+                vars.minimize_crossings = true;
+            },
+            MaxDepth(depth) => {
+                let depth = depth.eval(vars)?;
+                if !(depth >= 1.0 && depth <= MAX_DEPTH as f64 && depth.fract() == 0.0) {
+                    Err(errors::Error::max_depth(depth, MAX_DEPTH))?;
+                }
+                vars.max_depth = depth as usize;
+            },
         };
         Ok(())
     }
@@ -608,9 +1340,9 @@ impl Setup {
 
 #[derive(Clone, Debug)]
 pub enum Command {
-    Group(Vec<Command>, String),
-    Routes(Vec<RIdent>, String),
-    Stop(Stop, String),
+    Group(Vec<Command>, Str),
+    Routes(Vec<(RIdent, Span)>, Str),
+    Stop(Box<Stop>, Str, Str, Span),
 }
 
 impl Command {
@@ -627,19 +1359,25 @@ impl Command {
                 let v = v.into_iter()
                     .map(|c| c.eval(vars))
                     .collect::<Result<Vec<_>, Box<Error>>>()?;
-                Ok(Cmd::Group(v, s))
+                Ok(Cmd::Group(v, s.eval(vars)?))
             }
             Routes(v, s) => {
-                let v = v.into_iter()
-                    .map(|r| {
-                        let route = vars.get_route(&r)?.clone();
-                        let id = r.0;
-                        Ok(Cmd::Route(route, id))
-                    }).collect::<Result<Vec<_>, Box<Error>>>()?;
-                Ok(Cmd::Group(v, s))
-            },
-            Stop(st, id) => {
-                Ok(Cmd::Stop(st.eval(vars)?, id))
+                let mut routes = Vec::new();
+                for (r, span) in v {
+                    let r = r.interpolate(vars).map_err(|e| errors::locate(e, span))?;
+                    let route = vars.get_route(&r)
+                        .map_err(|e| errors::locate(e, span))?
+                        .clone();
+                    vars.record_route_ref(&r, span);
+                    routes.push(Cmd::Route(route, r.0));
+                }
+                Ok(Cmd::Group(routes, s.eval(vars)?))
+            },
+            Stop(st, label, id, span) => {
+                let st = st.eval(vars).map_err(|e| errors::locate(e, span))?;
+                let label = label.eval(vars).map_err(|e| errors::locate(e, span))?;
+                let id = id.eval(vars).map_err(|e| errors::locate(e, span))?;
+                Ok(Cmd::Stop(st, label, id, vars.doc.clone(), span))
             },
         }
     }
@@ -0,0 +1,819 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::mem;
+
+use regex::{self, Regex};
+
+use errors;
+
+use super::{Arg, Command, Definition, Expr, Line, Param, Point, Range, Route, Scalar, Segment,
+            Setup, Statement, Stop, Str};
+
+/// Collects the identifiers which an expression refers to. Macros are
+/// suffixed with `[]`.
+trait Uses {
+    fn uses(&self, names: &mut Vec<String>);
+}
+
+/// Adds `name`, and any scalars interpolated into it, to `names`.
+fn name(name: String, names: &mut Vec<String>) {
+    interpolated(&name, names);
+    names.push(name);
+}
+
+/// Adds the scalars interpolated into `name` to `names`.
+fn interpolated(name: &str, names: &mut Vec<String>) {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\{(\$\w+)\}").unwrap();
+    }
+    names.extend(RE.captures_iter(name).map(|caps| String::from(&caps[1])));
+}
+
+fn all<T: Uses>(exprs: &[T], names: &mut Vec<String>) {
+    for e in exprs {
+        e.uses(names);
+    }
+}
+
+impl<T: Uses> Uses for Box<T> {
+    fn uses(&self, names: &mut Vec<String>) {
+        (**self).uses(names)
+    }
+}
+
+impl<T: Uses> Uses for Option<T> {
+    fn uses(&self, names: &mut Vec<String>) {
+        if let Some(ref e) = *self {
+            e.uses(names);
+        }
+    }
+}
+
+impl Uses for Expr {
+    fn uses(&self, names: &mut Vec<String>) {
+        match *self {
+            Expr::Scalar(ref e) => e.uses(names),
+            Expr::Point(ref e) => e.uses(names),
+            Expr::Line(ref e) => e.uses(names),
+        }
+    }
+}
+
+impl Uses for Arg {
+    fn uses(&self, names: &mut Vec<String>) {
+        let e = match *self {
+            Arg::Positional(ref e) | Arg::Named(_, ref e) => e,
+        };
+        e.uses(names);
+    }
+}
+
+/// Adds the macro `id` and the identifiers used by the arguments to a call
+/// of it to `names`.
+///
+/// An argument naming an identifier passes a macro if the parameter it is
+/// given for has a signature, which is only known from the definition of
+/// the macro. Such arguments are added as `macro param arg[]`, to be
+/// resolved by `resolve_passed`.
+fn call(id: String, args: &[Arg], names: &mut Vec<String>) {
+    for (i, arg) in args.iter().enumerate() {
+        let (param, e) = match *arg {
+            Arg::Positional(ref e) => (i.to_string(), e),
+            Arg::Named(ref param, ref e) => (format!("{}", param), e),
+        };
+        if let Some(f) = macro_name(e) {
+            names.push(format!("{} {} {}", id, param, f));
+        }
+    }
+    name(id, names);
+    all(args, names);
+}
+
+/// Returns the macro which an argument may pass by name.
+fn macro_name(e: &Expr) -> Option<String> {
+    match *e {
+        Expr::Scalar(Scalar::Ident(ref id, _)) => Some(format!("{}[]", id)),
+        Expr::Point(Point::Ident(ref id, _)) => Some(format!("{}[]", id)),
+        Expr::Line(Line::Ident(ref id, _)) => Some(format!("{}[]", id)),
+        _ => None,
+    }
+}
+
+/// Resolves an argument which may pass a macro, added by `call`, using the
+/// parameters of the macros defined in `sigs`. Returns the macro passed, or
+/// `None` if the parameter has no signature. Arguments to macros which are
+/// not defined here, such as parameters, are taken to pass a macro.
+fn resolve_passed(id: &str, sigs: &HashMap<String, Vec<(String, bool)>>) -> Option<String> {
+    let parts = id.split(' ').collect::<Vec<_>>();
+    if parts.len() != 3 {
+        return Some(String::from(id));
+    }
+    let (callee, param, arg) = (parts[0], parts[1], parts[2]);
+    let params = match sigs.get(callee) {
+        Some(params) => params,
+        None => return Some(String::from(arg)),
+    };
+    let has_sig = match param.parse::<usize>() {
+        Ok(i) => i < params.len() && params[i].1,
+        Err(_) => params.iter().any(|&(ref p, sig)| p == param && sig),
+    };
+    if has_sig { Some(String::from(arg)) } else { None }
+}
+
+impl Uses for Scalar {
+    fn uses(&self, names: &mut Vec<String>) {
+        use super::Scalar::*;
+        match *self {
+            Add(ref a, ref b, _) | Mul(ref a, ref b, _) | Div(ref a, ref b, _) |
+            Less(ref a, ref b, _) | LessEq(ref a, ref b, _) | Equal(ref a, ref b, _) |
+            And(ref a, ref b, _) | Or(ref a, ref b, _) => {
+                a.uses(names);
+                b.uses(names);
+            },
+            Neg(ref a, _) | Not(ref a, _) => a.uses(names),
+            If(ref c, ref a, ref b, _) => {
+                c.uses(names);
+                a.uses(names);
+                b.uses(names);
+            },
+            Num(..) => {},
+            Ident(ref id, _) => name(format!("{}", id), names),
+            Macro(ref id, ref args, _) => call(format!("{}[]", id), args, names),
+            Call(_, ref args, _) => all(args, names),
+        }
+    }
+}
+
+impl Uses for Point {
+    fn uses(&self, names: &mut Vec<String>) {
+        use super::Point::*;
+        match *self {
+            Add(ref a, ref b, _) => {
+                a.uses(names);
+                b.uses(names);
+            },
+            Neg(ref a, _) => a.uses(names),
+            Mul(ref a, ref x, _) | Div(ref a, ref x, _) => {
+                a.uses(names);
+                x.uses(names);
+            },
+            Pair(ref x, ref y, _) => {
+                x.uses(names);
+                y.uses(names);
+            },
+            Intersection(ref a, ref b, _) => {
+                a.uses(names);
+                b.uses(names);
+            },
+            If(ref c, ref a, ref b, _) => {
+                c.uses(names);
+                a.uses(names);
+                b.uses(names);
+            },
+            Ident(ref id, _) => name(format!("{}", id), names),
+            Macro(ref id, ref args, _) => call(format!("{}[]", id), args, names),
+            Call(_, ref args, _) => all(args, names),
+        }
+    }
+}
+
+impl Uses for Line {
+    fn uses(&self, names: &mut Vec<String>) {
+        use super::Line::*;
+        match *self {
+            Add(ref l, ref p, _) | Parallel(ref l, ref p, _) | Perpendicular(ref l, ref p, _) => {
+                l.uses(names);
+                p.uses(names);
+            },
+            Offset(ref l, ref x, _) => {
+                l.uses(names);
+                x.uses(names);
+            },
+            Vector(ref p, ref q, _) => {
+                p.uses(names);
+                q.uses(names);
+            },
+            Angle(ref p, ref x, _) => {
+                p.uses(names);
+                x.uses(names);
+            },
+            If(ref c, ref a, ref b, _) => {
+                c.uses(names);
+                a.uses(names);
+                b.uses(names);
+            },
+            Ident(ref id, _) => name(format!("{}", id), names),
+            Macro(ref id, ref args, _) => call(format!("{}[]", id), args, names),
+            Call(_, ref args, _) => all(args, names),
+        }
+    }
+}
+
+impl Uses for Str {
+    fn uses(&self, names: &mut Vec<String>) {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r"\{([$#](?:\w+::)*\w+)\}").unwrap();
+        }
+        match *self {
+            Str::Lit(ref s, _) => {
+                names.extend(RE.captures_iter(s).map(|caps| String::from(&caps[1])));
+            },
+            Str::Concat(ref a, ref b, _) => {
+                a.uses(names);
+                b.uses(names);
+            },
+            Str::Ident(ref id, _) => name(format!("{}", id), names),
+            Str::Macro(ref id, ref args, _) => call(format!("{}[]", id), args, names),
+        }
+    }
+}
+
+impl Uses for Segment {
+    fn uses(&self, names: &mut Vec<String>) {
+        self.start.uses(names);
+        self.end.uses(names);
+    }
+}
+
+impl Uses for Route {
+    fn uses(&self, names: &mut Vec<String>) {
+        match *self {
+            Route::Start(ref seg, ref off, _) => {
+                seg.uses(names);
+                off.uses(names);
+            },
+            Route::Extend(ref r, ref off, ref p, _) => {
+                r.uses(names);
+                off.uses(names);
+                p.uses(names);
+            },
+            Route::Concat(ref a, ref b, _) => {
+                a.uses(names);
+                b.uses(names);
+            },
+            Route::Macro(ref id, ref args, _) => call(format!("{}[]", id), args, names),
+        }
+    }
+}
+
+impl Uses for Stop {
+    fn uses(&self, names: &mut Vec<String>) {
+        match *self {
+            Stop::Line(ref seg, ref line, ..) => {
+                seg.uses(names);
+                line.uses(names);
+            },
+            Stop::Segment(ref a, ref b, ..) => {
+                a.uses(names);
+                b.uses(names);
+            },
+            Stop::Macro(ref id, ref args, _) => call(format!("{}[]", id), args, names),
+        }
+    }
+}
+
+impl Uses for Range {
+    fn uses(&self, names: &mut Vec<String>) {
+        match *self {
+            Range::Between(ref a, ref b, _) => {
+                a.uses(names);
+                b.uses(names);
+            },
+            Range::List(ref v, _) => all(v, names),
+        }
+    }
+}
+
+impl Uses for Setup {
+    fn uses(&self, names: &mut Vec<String>) {
+        use super::Setup::*;
+        match *self {
+            RSep(ref x) | RBase(ref x) | MaxDepth(ref x) => x.uses(names),
+            Bounds(ref x0, ref y0, ref x1, ref y1) => all(&[x0, y0, x1, y1], names),
+            BoundsPoints(ref p0, ref p1) => {
+                p0.uses(names);
+                p1.uses(names);
+            },
+            Style(_) | AutoOffsets | MinimizeCrossings => {},
+        }
+    }
+}
+
+impl<T: Uses> Uses for &T {
+    fn uses(&self, names: &mut Vec<String>) {
+        (**self).uses(names)
+    }
+}
+
+impl Uses for Command {
+    fn uses(&self, names: &mut Vec<String>) {
+        match *self {
+            Command::Group(ref v, ref s) => {
+                all(v, names);
+                s.uses(names);
+            },
+            Command::Routes(ref v, ref s) => {
+                for (id, _) in v {
+                    name(format!("{}", id), names);
+                }
+                s.uses(names);
+            },
+            Command::Stop(ref st, ref label, ref id, _) => {
+                st.uses(names);
+                label.uses(names);
+                id.uses(names);
+            },
+        }
+    }
+}
+
+/// Collects the identifiers used by the body of a macro and the defaults of
+/// its parameters, other than the parameters themselves.
+fn body<T: Uses>(params: &[Param], body: &T, names: &mut Vec<String>) {
+    let mut args = params.iter().map(|p| format!("{}", p.id)).collect::<Vec<_>>();
+    args.extend(params.iter().filter(|p| p.sig.is_some()).map(|p| format!("{}[]", p.id)));
+    let mut used = Vec::new();
+    for p in params {
+        p.default.uses(&mut used);
+        if let (Some(_), Some(e)) = (&p.sig, &p.default) {
+            used.extend(macro_name(e));
+        }
+    }
+    body.uses(&mut used);
+    names.extend(used.into_iter().filter(|id| !bound_by(id, &args)));
+}
+
+/// Whether `id` is one of the parameters `args`. An argument added by `call`
+/// is bound if either the macro called or the argument is a parameter, as a
+/// parameter can't take macros as arguments.
+fn bound_by(id: &str, args: &[String]) -> bool {
+    let parts = id.split(' ').collect::<Vec<_>>();
+    match parts.len() {
+        3 => args.iter().any(|a| a == parts[0] || a == parts[2]),
+        _ => args.iter().any(|a| a == id),
+    }
+}
+
+/// Returns the macro defined by a statement and its parameters, with whether
+/// each has a signature.
+fn signature(st: &Statement) -> Option<(String, Vec<(String, bool)>)> {
+    use super::Definition::*;
+    match *st {
+        Statement::Definition(ref d, _) | Statement::Redefinition(ref d, _) => {
+            let params = match *d {
+                ScalarMacro(_, ref params, _) | PointMacro(_, ref params, _) |
+                LineMacro(_, ref params, _) | RouteMacro(_, ref params, _) |
+                StopMacro(_, ref params, _) | StrMacro(_, ref params, _) => params,
+                _ => return None,
+            };
+            let params = params.iter().map(|p| (format!("{}", p.id), p.sig.is_some())).collect();
+            Some((d.ident(), params))
+        },
+        Statement::Doc(_, ref st) => signature(st),
+        _ => None,
+    }
+}
+
+impl Uses for Definition {
+    fn uses(&self, names: &mut Vec<String>) {
+        use super::Definition::*;
+        interpolated(&self.ident(), names);
+        match *self {
+            Scalar(_, ref val) => val.uses(names),
+            Point(_, ref val) => val.uses(names),
+            Line(_, ref val) => val.uses(names),
+            Route(_, ref val) => val.uses(names),
+            Str(_, ref val) => val.uses(names),
+            ScalarMacro(_, ref args, ref val) => body(args, val, names),
+            PointMacro(_, ref args, ref val) => body(args, val, names),
+            LineMacro(_, ref args, ref val) => body(args, val, names),
+            RouteMacro(_, ref args, ref val) => body(args, val, names),
+            StopMacro(_, ref args, ref val) => body(args, &**val, names),
+            StrMacro(_, ref args, ref val) => body(args, val, names),
+        }
+    }
+}
+
+impl Uses for Statement {
+    fn uses(&self, names: &mut Vec<String>) {
+        match *self {
+            Statement::Definition(ref d, _) | Statement::Redefinition(ref d, _) => d.uses(names),
+            Statement::Setup(ref s, _) => s.uses(names),
+            Statement::Command(ref c, _) => c.uses(names),
+            Statement::For(ref id, ref range, ref body, _) => {
+                range.uses(names);
+                let local = defined(self);
+                let var = format!("{}", id);
+                let mut used = Vec::new();
+                all(body, &mut used);
+                names.extend(used.into_iter().filter(|id| *id != var && !local.contains(id)));
+            },
+            Statement::Doc(_, ref st) => st.uses(names),
+            // modules are evaluated in a scope of their own
+            Statement::Module(..) | Statement::Include(..) | Statement::Import(..) |
+            Statement::None => {},
+        }
+    }
+}
+
+/// Returns the identifiers defined by a statement. A module `m` defines
+/// `m::`.
+fn defined(st: &Statement) -> Vec<String> {
+    match *st {
+        Statement::Definition(ref d, _) | Statement::Redefinition(ref d, _) => vec![d.ident()],
+        Statement::For(_, _, ref body, _) => body.iter().flat_map(defined).collect(),
+        Statement::Module(ref name, _, _) => vec![format!("{}::", name)],
+        Statement::Doc(_, ref st) => defined(st),
+        _ => Vec::new(),
+    }
+}
+
+/// Returns the name under which the definition of `id` is found: `m::` for a
+/// qualified identifier `@m::x`, and `id` itself otherwise.
+fn key(id: &str) -> &str {
+    match id.find("::") {
+        Some(i) => &id[1..i + 2],
+        None => id,
+    }
+}
+
+/// Returns the name under which definitions of `id` are grouped: the sigil
+/// alone for an identifier built by interpolation, and `id` otherwise.
+fn class(id: &str) -> &str {
+    if id.contains('{') { &id[..1] } else { id }
+}
+
+/// Whether the identifier `pattern`, built by interpolation, can evaluate to
+/// `id`.
+fn matches(pattern: &str, id: &str) -> bool {
+    lazy_static! {
+        static ref RE: Regex = Regex::new(r"\{\$\w+\}").unwrap();
+    }
+    let mut re = String::from("^");
+    let mut last = 0;
+    for m in RE.find_iter(pattern) {
+        re.push_str(&regex::escape(&pattern[last..m.start()]));
+        re.push_str("-?[0-9]+");
+        last = m.end();
+    }
+    re.push_str(&regex::escape(&pattern[last..]));
+    re.push('$');
+    Regex::new(&re).unwrap().is_match(id)
+}
+
+/// Returns the macro defined by a statement, if it is a macro definition.
+fn macro_def(st: &Statement) -> Option<String> {
+    match *st {
+        Statement::Definition(ref d, _) | Statement::Redefinition(ref d, _) => {
+            Some(d.ident()).filter(|id| id.ends_with("[]"))
+        },
+        Statement::Doc(_, ref st) => macro_def(st),
+        _ => None,
+    }
+}
+
+/// Adds the identifiers used by the bodies of the macros in `names`, and by
+/// the macros those use in turn.
+fn expand(names: &mut Vec<String>, bodies: &HashMap<String, Vec<String>>) {
+    let mut seen = HashSet::new();
+    let mut i = 0;
+    while i < names.len() {
+        if let Some(body) = bodies.get(&names[i]) {
+            if seen.insert(names[i].clone()) {
+                names.extend(body.iter().cloned());
+            }
+        }
+        i += 1;
+    }
+}
+
+/// Whether a statement has an effect other than defining identifiers, so
+/// that it must stay in order with other such statements.
+fn effect(st: &Statement) -> bool {
+    match *st {
+        Statement::Setup(..) | Statement::Command(..) => true,
+        Statement::For(_, _, ref body, _) => body.iter().any(effect),
+        Statement::Doc(_, ref st) => effect(st),
+        _ => false,
+    }
+}
+
+/// Orders statements so that each definition is evaluated before the
+/// statements using it, keeping the source order where possible.
+///
+/// A use refers to the closest earlier definition of the identifier, or else
+/// to the first later one, so redefinitions still apply from where they are
+/// written. Identifiers built by interpolation are only known once evaluated,
+/// so statements using them stay after every earlier statement defining an
+/// interpolated identifier of the same type, and after the definitions of
+/// the identifiers they could evaluate to. A use of an identifier with no
+/// definition of its own depends on every statement defining an
+/// interpolated identifier which could evaluate to it, such as a loop.
+/// Setup and commands keep their order relative to each other.
+///
+/// Statements in a definition cycle are dropped, and an error is returned
+/// for each cycle.
+pub fn order(statements: Vec<Statement>) -> (Vec<Statement>, Vec<errors::Error>) {
+    let mut errs = Vec::new();
+    let statements = statements.into_iter()
+        .map(|st| order_body(st, &mut errs))
+        .collect::<Vec<_>>();
+    let n = statements.len();
+    let defs = statements.iter().map(defined).collect::<Vec<_>>();
+    let mut definers = HashMap::new();
+    for (i, names) in defs.iter().enumerate() {
+        for id in names {
+            definers.entry(class(id)).or_insert_with(Vec::new).push(i);
+        }
+    }
+    let mut uses = statements.iter().map(|st| {
+        let mut names = Vec::new();
+        st.uses(&mut names);
+        names
+    }).collect::<Vec<_>>();
+    // a macro's body is only evaluated where the macro is used, so uses of
+    // the macro depend on what its body uses rather than the definition
+    // itself, which allows macros to be recursive
+    let mut bodies = HashMap::new();
+    for (i, st) in statements.iter().enumerate() {
+        if let Some(id) = macro_def(st) {
+            let body = mem::take(&mut uses[i]);
+            interpolated(&id, &mut uses[i]);
+            bodies.entry(id).or_insert_with(Vec::new).extend(body);
+        }
+    }
+    let sigs = statements.iter().filter_map(signature).collect::<HashMap<_, _>>();
+    for names in uses.iter_mut().chain(bodies.values_mut()) {
+        *names = names.drain(..).filter_map(|id| resolve_passed(&id, &sigs)).collect();
+    }
+    for names in uses.iter_mut() {
+        expand(names, &bodies);
+    }
+    // the statements which each one depends on, and the identifier used
+    let mut deps = vec![Vec::new(); n];
+    let mut last_effect = None;
+    for (i, (st, names)) in statements.iter().zip(uses).enumerate() {
+        for id in names {
+            let k = if id.contains("::") { key(&id) } else { class(&id) };
+            if k.len() == 1 {
+                if let Some(js) = definers.get(k) {
+                    deps[i].extend(js.iter().filter(|&&j| j < i).map(|&j| (j, id.clone())));
+                }
+                for (other, js) in definers.iter() {
+                    if other.len() > 1 && !other.contains("::") && matches(&id, other) {
+                        let j = js.iter().rev().find(|&&j| j < i).map_or(js[0], |&j| j);
+                        if j != i {
+                            deps[i].push((j, String::from(*other)));
+                        }
+                    }
+                }
+                continue;
+            }
+            let js = match definers.get(k) {
+                Some(js) => js,
+                None => {
+                    if let Some(js) = definers.get(&id[..1]) {
+                        let patterns = js.iter()
+                            .filter(|&&j| j != i && defs[j].iter().any(|d| matches(d, &id)));
+                        deps[i].extend(patterns.map(|&j| (j, id.clone())));
+                    }
+                    continue;
+                },
+            };
+            let j = js.iter().rev().find(|&&j| j < i).map_or(js[0], |&j| j);
+            deps[i].push((j, id.clone()));
+            // a redefinition must wait for the uses of the old definition
+            if let Some(&next) = js.iter().find(|&&k| k > j && k != i) {
+                deps[next].push((i, defs[i].first().cloned().unwrap_or_default()));
+            }
+        }
+        for id in &defs[i] {
+            if let Some(&j) = definers[class(id)].iter().rev().find(|&&j| j < i) {
+                deps[i].push((j, id.clone()));
+            }
+        }
+        if effect(st) {
+            if let Some(j) = last_effect {
+                deps[i].push((j, defs[j].first().cloned().unwrap_or_default()));
+            }
+            last_effect = Some(i);
+        }
+    }
+    for ds in &mut deps {
+        ds.sort_by_key(|&(j, _)| j);
+        ds.dedup_by_key(|&mut (j, _)| j);
+    }
+
+    let mut dependents = vec![Vec::new(); n];
+    let mut waiting = vec![0; n];
+    for (i, ds) in deps.iter().enumerate() {
+        for &(j, _) in ds {
+            dependents[j].push(i);
+            waiting[i] += 1;
+        }
+    }
+    let mut statements = statements.into_iter().map(Some).collect::<Vec<_>>();
+    let mut done = vec![false; n];
+    let mut ready = (0..n).filter(|&i| waiting[i] == 0).map(Reverse).collect::<BinaryHeap<_>>();
+    let mut out = Vec::new();
+    loop {
+        let i = match ready.pop() {
+            Some(Reverse(i)) => i,
+            None => {
+                let cycle = match (0..n).find(|&i| !done[i]) {
+                    Some(i) => find_cycle(i, &deps, &done),
+                    None => break,
+                };
+                let span = statements[cycle[0].0].as_ref().and_then(|st| st.span());
+                let mut chain = cycle.iter().map(|(_, id)| id.clone()).collect::<Vec<_>>();
+                let first = chain[0].clone();
+                chain.push(first);
+                let err = errors::Error::definition_cycle(chain);
+                errs.push(match span {
+                    Some(span) => err.at(span),
+                    None => err,
+                });
+                for &(i, _) in &cycle {
+                    statements[i] = None;
+                    finish(i, &mut done, &mut waiting, &dependents, &mut ready);
+                }
+                continue;
+            },
+        };
+        out.extend(statements[i].take());
+        finish(i, &mut done, &mut waiting, &dependents, &mut ready);
+    }
+    (out, errs)
+}
+
+fn finish(i: usize,
+          done: &mut [bool],
+          waiting: &mut [usize],
+          dependents: &[Vec<usize>],
+          ready: &mut BinaryHeap<Reverse<usize>>) {
+    if done[i] {
+        return;
+    }
+    done[i] = true;
+    for &k in &dependents[i] {
+        waiting[k] -= 1;
+        if waiting[k] == 0 && !done[k] {
+            ready.push(Reverse(k));
+        }
+    }
+}
+
+/// Follows dependencies from the unfinished statement `i` until one repeats,
+/// returning the cycle found. Each statement is paired with the identifier
+/// through which the previous one depends on it, and the cycle starts at its
+/// earliest statement.
+fn find_cycle(i: usize, deps: &[Vec<(usize, String)>], done: &[bool]) -> Vec<(usize, String)> {
+    let mut path: Vec<(usize, String)> = Vec::new();
+    let mut i = i;
+    loop {
+        // every unfinished statement waits on another unfinished statement
+        let &(j, ref id) = deps[i].iter().find(|&&(j, _)| !done[j]).unwrap();
+        if let Some(k) = path.iter().position(|&(k, _)| k == j) {
+            path.push((j, id.clone()));
+            let mut cycle = path.split_off(k + 1);
+            let start = (0..cycle.len()).min_by_key(|&k| cycle[k].0).unwrap();
+            cycle.rotate_left(start);
+            return cycle;
+        }
+        path.push((j, id.clone()));
+        i = j;
+    }
+}
+
+/// Orders the bodies of loops and modules.
+fn order_body(st: Statement, errs: &mut Vec<errors::Error>) -> Statement {
+    match st {
+        Statement::For(id, range, body, span) => {
+            let (body, e) = order(body);
+            errs.extend(e);
+            Statement::For(id, range, body, span)
+        },
+        Statement::Module(name, body, span) => {
+            let (body, e) = order(body);
+            errs.extend(e);
+            Statement::Module(name, body, span)
+        },
+        Statement::Doc(doc, st) => Statement::Doc(doc, Box::new(order_body(*st, errs))),
+        st => st,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use grammar;
+    use lexer::Lexer;
+
+    use super::*;
+
+    /// Orders `src`, returning the identifiers defined by each statement in
+    /// the order they are evaluated, and the cycles found.
+    fn ordered(src: &str) -> (Vec<String>, Vec<errors::Error>) {
+        let prog = grammar::parse_Program(&mut Vec::new(), 0, Lexer::new(src)).unwrap();
+        let (prog, errs) = order(prog);
+        (prog.iter().map(|st| defined(st).join(" ")).collect(), errs)
+    }
+
+    #[test]
+    fn forward_references() {
+        let (order, errs) = ordered("@a = @b + (1, 0); @b = $x * (1, 1); $x = 2;");
+        assert_eq!(order, ["$x", "@b", "@a"]);
+        assert!(errs.is_empty());
+    }
+
+    #[test]
+    fn source_order_kept() {
+        let (order, _) = ordered("$c = 1; $a = 2; $b = $a;");
+        assert_eq!(order, ["$c", "$a", "$b"]);
+    }
+
+    #[test]
+    fn commands_stay_in_order() {
+        let (order, _) = ordered("\\r_sep 1; \\r_base $x; $x = 3; \\style \"a.css\";");
+        assert_eq!(order, ["", "$x", "", ""]);
+    }
+
+    #[test]
+    fn cycles() {
+        let (order, errs) = ordered("$a = $b; $b = $c; $c = $a; $d = 1;");
+        assert_eq!(order, ["$d"]);
+        assert_eq!(errs.len(), 1);
+        match *errs[0].kind() {
+            errors::ErrorKind::DefinitionCycle { ref chain } => {
+                assert_eq!(*chain, ["$a", "$b", "$c", "$a"]);
+            },
+            ref kind => panic!("unexpected error {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn recursive_macros() {
+        let (order, errs) =
+            ordered("$y = $f[3]; $f[$n] = if $n <= 0 then $z else $f[$n - 1]; $z = 0;");
+        assert_eq!(order, ["$f[]", "$z", "$y"]);
+        assert!(errs.is_empty());
+    }
+
+    #[test]
+    fn redefine_chain() {
+        let src = "$a = 1; $b = $a; \\redefine $a = $a + 1; $c = $a; \\redefine $a = $c;";
+        let (order, errs) = ordered(src);
+        assert_eq!(order, ["$a", "$b", "$a", "$c", "$a"]);
+        assert!(errs.is_empty());
+    }
+
+    #[test]
+    fn redefinition_waits_for_uses() {
+        let (order, _) = ordered("$b = $a + $x; $a = 1; \\redefine $a = 2; $x = 1;");
+        assert_eq!(order, ["$a", "$x", "$b", "$a"]);
+    }
+
+    #[test]
+    fn loops() {
+        let (order, errs) = ordered("\\for $i in 0..$n { $a_{$i} = $i * $k; } $n = 3; $k = 2;");
+        assert_eq!(order, ["$n", "$k", "$a_{$i}"]);
+        assert!(errs.is_empty());
+    }
+
+    #[test]
+    fn loop_generated_uses() {
+        let src = "@x = @st_1 + (1, 0); \\for $i in 0..3 { @st_{$i} = ($i * $k, 0); } $k = 10;";
+        let (order, errs) = ordered(src);
+        assert_eq!(order, ["$k", "@st_{$i}", "@x"]);
+        assert!(errs.is_empty());
+    }
+
+    #[test]
+    fn interpolated_uses() {
+        let (order, _) = ordered("$j = 2; @y = @a_{$j}; @a_2 = (5, 5);");
+        assert_eq!(order, ["$j", "@a_2", "@y"]);
+    }
+
+    #[test]
+    fn passed_macros() {
+        let src = "$r = $apply[$g, 3]; $apply[$f[$x], $y] = $f[$y]; $g[$x] = $x + $k; $k = 1;";
+        let (order, errs) = ordered(src);
+        assert_eq!(order, ["$apply[]", "$g[]", "$k", "$r"]);
+        assert!(errs.is_empty());
+    }
+
+    #[test]
+    fn scalar_arguments_pass_no_macro() {
+        let (order, errs) = ordered("$m[$a] = $a; $y = $m[$x]; $x[$k] = $y; $x = 1;");
+        assert_eq!(order, ["$m[]", "$x[]", "$x", "$y"]);
+        assert!(errs.is_empty());
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches("@st_{$i}", "@st_1"));
+        assert!(matches("@st_{$i}_{$j}", "@st_-1_20"));
+        assert!(!matches("@st_{$i}", "@st_x"));
+        assert!(!matches("@st_{$i}", "@st_1_2"));
+        assert!(matches("$f_{$i}[]", "$f_0[]"));
+    }
+}
@@ -1,13 +1,29 @@
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::io::prelude::*;
 use std::error::Error;
 
 use math;
 use route;
 use errors;
+use errors::Span;
+use layout;
 
 use command::Command;
-use super::{Definition, Ident, SIdent, PIdent, LIdent, RIdent, Eval, Scalar, Point, Line, Route, Macro};
+use super::{Definition, Ident, SIdent, PIdent, LIdent, RIdent, StIdent, StrIdent, Eval};
+use super::{Scalar, Point, Line, Route, Stop, Str, Macro, Statement};
+
+/// The stack space that nested macro expansions can use, leaving room for
+/// evaluating the expressions in the innermost expansion.
+const EXPANSION_STACK: usize = super::STACK_SIZE - 16 * 1024 * 1024;
+
+/// Returns the address of a local variable, as the position of the top of
+/// the stack.
+#[inline(never)]
+fn stack_position() -> usize {
+    let top = 0u8;
+    &top as *const u8 as usize
+}
 
 #[derive(Clone, Debug)]
 pub struct Variables<'a> {
@@ -15,26 +31,102 @@ pub struct Variables<'a> {
     points: HashMap<PIdent, math::Point>,
     lines: HashMap<LIdent, math::Line>,
     routes: HashMap<RIdent, route::Route>,
+    strings: HashMap<StrIdent, String>,
     segments: HashMap<route::Segment, SegmentBase>,
     scalar_macros: HashMap<SIdent, Macro<Scalar>>,
     point_macros: HashMap<PIdent, Macro<Point>>,
     line_macros: HashMap<LIdent, Macro<Line>>,
+    route_macros: HashMap<RIdent, Macro<Route>>,
+    stop_macros: HashMap<StIdent, Macro<Stop>>,
+    string_macros: HashMap<StrIdent, Macro<Str>>,
+    modules: HashMap<String, Variables<'static>>,
     commands: Vec<Command>,
     globals: Option<&'a Variables<'a>>,
+    /// The scopes which macros passed as arguments were passed from.
+    closures: HashMap<String, &'a Variables<'a>>,
+    definitions: Vec<(String, Span)>,
+    route_refs: Vec<(String, Span)>,
+    redefinitions: Vec<(String, Span, Span)>,
+    /// The identifiers defined anywhere in the program, which may not have
+    /// been evaluated yet.
+    declared: Vec<String>,
+    used: RefCell<HashSet<String>>,
     pub r_sep: f64,
     pub r_base: f64,
     pub bounds: (math::Point, math::Point),
     pub style: Vec<String>,
+    pub auto_offsets: bool,
+    pub minimize_crossings: bool,
+    /// The number of macro expansions this scope is nested in.
+    depth: usize,
+    /// The maximum number of nested macro expansions.
+    pub max_depth: usize,
+    /// The position of the stack when evaluation began.
+    stack_base: usize,
+    /// Whether redefinitions without `\redefine` are only warned about.
+    pub warn_redefinitions: bool,
+    /// The doc comment on the statement being evaluated.
+    pub doc: Option<String>,
 }
 
 macro_rules! get_typed {
-    ($f:ident, $m:ident, $id_t:ty, $out_t:ty) => {
+    ($f:ident, $m:ident, $id_t:ident, $out_t:ty, $is_macro:expr) => {
         pub fn $f(&self, id: &$id_t) -> Result<&$out_t, Box<Error>> {
-            self.$m.get(&id).map_or_else(
-                || self.globals.as_ref().map_or(
-                    Err(errors::Error::undefined(format!("{}", id).as_ref()))?,
-                    |g| g.$f(id)),
-                |val| Ok(val))
+            let id = &id.interpolate(self)?;
+            let mut scope = Some(self);
+            while let Some(vars) = scope {
+                if let Some(val) = vars.$m.get(id) {
+                    let name = format!("{}{}", id, if $is_macro { "[]" } else { "" });
+                    vars.used.borrow_mut().insert(name);
+                    return Ok(val);
+                }
+                scope = vars.globals;
+            }
+            if let Some(i) = id.0.find("::") {
+                let rest = $id_t(String::from(&id.0[i + 2..]));
+                return self.get_module(&id.0[..i])?.$f(&rest)
+                    .map_err(|e| errors::qualify(e, &id.0[..i]));
+            }
+            Err(self.undefined(format!("{}", id).as_ref(), $is_macro))?
+        }
+    }
+}
+
+macro_rules! get_macro {
+    ($f:ident, $m:ident, $id_t:ident, $expr_t:ty) => {
+        /// Looks up a macro, along with the scope in which to expand it: the
+        /// scope it is defined in or, for a macro passed as an argument, the
+        /// scope it was passed from.
+        pub fn $f<'b>(&'b self, id: &$id_t) -> Result<(&'b Macro<$expr_t>, &'b Variables<'b>),
+                                                       Box<Error>> {
+            let id = &id.interpolate(self)?;
+            let mut scope: Option<&'b Variables<'b>> = Some(self);
+            while let Some(vars) = scope {
+                if let Some(m) = vars.$m.get(id) {
+                    vars.used.borrow_mut().insert(format!("{}[]", id));
+                    let env = vars.closures.get(&format!("{}", id)).cloned().unwrap_or(vars);
+                    return Ok((m, env));
+                }
+                scope = vars.globals;
+            }
+            if let Some(i) = id.0.find("::") {
+                let rest = $id_t(String::from(&id.0[i + 2..]));
+                return self.get_module(&id.0[..i])?.$f(&rest)
+                    .map_err(|e| errors::qualify(e, &id.0[..i]));
+            }
+            Err(self.undefined(format!("{}", id).as_ref(), true))?
+        }
+    }
+}
+
+macro_rules! bind_typed {
+    ($f:ident, $m:ident, $id_t:ty, $out_t:ty) => {
+        /// Sets or removes a value directly, returning the previous value.
+        pub fn $f(&mut self, id: $id_t, val: Option<$out_t>) -> Option<$out_t> {
+            match val {
+                Some(val) => self.$m.insert(id, val),
+                None => self.$m.remove(&id),
+            }
         }
     }
 }
@@ -56,22 +148,71 @@ impl<'a> Variables<'a> {
             points: HashMap::new(),
             lines: HashMap::new(),
             routes: HashMap::new(),
+            strings: HashMap::new(),
             segments: HashMap::new(),
             scalar_macros: HashMap::new(),
             point_macros: HashMap::new(),
             line_macros: HashMap::new(),
+            route_macros: HashMap::new(),
+            stop_macros: HashMap::new(),
+            string_macros: HashMap::new(),
+            modules: HashMap::new(),
             commands: Vec::new(),
             globals: None,
+            closures: HashMap::new(),
+            definitions: Vec::new(),
+            route_refs: Vec::new(),
+            redefinitions: Vec::new(),
+            declared: Vec::new(),
+            used: RefCell::new(HashSet::new()),
             r_sep: 0.0,
             r_base: 0.0,
             bounds: (math::Point(0.0, 0.0), math::Point(0.0, 0.0)),
             style: Vec::new(),
+            auto_offsets: false,
+            minimize_crossings: false,
+            depth: 0,
+            max_depth: 100,
+            stack_base: stack_position(),
+            warn_redefinitions: false,
+            doc: None,
         }
     }
 
-    pub fn with_globals(globals: &'a Variables) -> Variables<'a> {
-        Variables {
+    /// Creates the scope in which a macro called from `caller` is expanded,
+    /// enclosed by `globals`. Returns `None` if the expansion would be nested
+    /// more deeply than `max_depth`.
+    pub fn nested(globals: &'a Variables, caller: &Variables) -> Option<Variables<'a>> {
+        if caller.depth >= caller.max_depth {
+            return None;
+        }
+        Some(Variables {
             globals: Some(globals),
+            depth: caller.depth + 1,
+            max_depth: caller.max_depth,
+            stack_base: caller.stack_base,
+            ..Variables::new()
+        })
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth
+    }
+
+    /// Whether so much of the stack has been used that another macro
+    /// expansion might overflow it.
+    pub fn stack_exhausted(&self) -> bool {
+        let pos = stack_position();
+        self.stack_base.max(pos) - self.stack_base.min(pos) > EXPANSION_STACK
+    }
+
+    /// Creates the scope into which a file imported from this one is loaded,
+    /// with the same settings for evaluating it.
+    pub fn module(&self) -> Variables<'static> {
+        Variables {
+            max_depth: self.max_depth,
+            stack_base: self.stack_base,
+            warn_redefinitions: self.warn_redefinitions,
             ..Variables::new()
         }
     }
@@ -84,20 +225,120 @@ impl<'a> Variables<'a> {
         }
     }
 
-    get_typed!(get_scalar, scalars, SIdent, math::Scalar);
-    get_typed!(get_point, points, PIdent, math::Point);
-    get_typed!(get_line, lines, LIdent, math::Line);
-    get_typed!(get_route, routes, RIdent, route::Route);
-    get_typed!(get_scalar_macro, scalar_macros, SIdent, Macro<Scalar>);
-    get_typed!(get_point_macro, point_macros, PIdent, Macro<Point>);
-    get_typed!(get_line_macro, line_macros, LIdent, Macro<Line>);
+    get_typed!(get_scalar, scalars, SIdent, math::Scalar, false);
+    get_typed!(get_point, points, PIdent, math::Point, false);
+    get_typed!(get_line, lines, LIdent, math::Line, false);
+    get_typed!(get_route, routes, RIdent, route::Route, false);
+    get_typed!(get_string, strings, StrIdent, String, false);
+    get_macro!(get_scalar_macro, scalar_macros, SIdent, Scalar);
+    get_macro!(get_point_macro, point_macros, PIdent, Point);
+    get_macro!(get_line_macro, line_macros, LIdent, Line);
+    get_macro!(get_route_macro, route_macros, RIdent, Route);
+    get_macro!(get_stop_macro, stop_macros, StIdent, Stop);
+    get_macro!(get_string_macro, string_macros, StrIdent, Str);
+
+    /// Records the identifiers defined by `prog`, so that they can be
+    /// suggested for misspelled identifiers before they have been evaluated.
+    pub fn declare(&mut self, prog: &[Statement]) {
+        // identifiers defined in loops are only known once evaluated
+        self.declared.extend(prog.iter()
+            .filter_map(|st| st.defines())
+            .filter(|id| !id.contains('{')));
+    }
+
+    /// Builds the error for an undefined identifier, suggesting similarly
+    /// named identifiers of the same type from this and any enclosing scope,
+    /// and noting identifiers with the same name but a different type.
+    fn undefined(&self, id: &str, is_macro: bool) -> errors::Error {
+        let (sigil, name) = id.split_at(1);
+        let names = self.names();
+        let mut close = names.iter()
+            .filter(|&&(ref n, m)| m == is_macro && n.starts_with(sigil))
+            .map(|(n, _)| (distance(&n[1..], name), n.clone()))
+            .filter(|&(d, _)| d <= (name.chars().count() / 3).max(1))
+            .collect::<Vec<_>>();
+        close.sort();
+        close.dedup();
+        let suggestions = close.into_iter()
+            .take(3)
+            .map(|(_, n)| if is_macro { n + "[]" } else { n })
+            .collect();
+        let mut others = names.into_iter()
+            .filter(|&(ref n, m)| &n[1..] == name && (m != is_macro || !n.starts_with(sigil)))
+            .map(|(n, m)| if m { n + "[]" } else { n })
+            .collect::<Vec<_>>();
+        others.sort();
+        others.dedup();
+        errors::Error::undefined(id, suggestions, others)
+    }
+
+    /// Returns the names of all identifiers visible from this scope, and
+    /// whether each is a macro.
+    fn names(&self) -> Vec<(String, bool)> {
+        let mut names = Vec::new();
+        names.extend(self.scalars.keys().map(|id| (format!("{}", id), false)));
+        names.extend(self.points.keys().map(|id| (format!("{}", id), false)));
+        names.extend(self.lines.keys().map(|id| (format!("{}", id), false)));
+        names.extend(self.routes.keys().map(|id| (format!("{}", id), false)));
+        names.extend(self.strings.keys().map(|id| (format!("{}", id), false)));
+        names.extend(self.scalar_macros.keys().map(|id| (format!("{}", id), true)));
+        names.extend(self.point_macros.keys().map(|id| (format!("{}", id), true)));
+        names.extend(self.line_macros.keys().map(|id| (format!("{}", id), true)));
+        names.extend(self.route_macros.keys().map(|id| (format!("{}", id), true)));
+        names.extend(self.stop_macros.keys().map(|id| (format!("{}", id), true)));
+        names.extend(self.string_macros.keys().map(|id| (format!("{}", id), true)));
+        names.extend(self.declared.iter().map(|id| match id.strip_suffix("[]") {
+            Some(id) => (String::from(id), true),
+            None => (id.clone(), false),
+        }));
+        if let Some(globals) = self.globals {
+            names.extend(globals.names());
+        }
+        names
+    }
+
+    bind_typed!(bind_scalar, scalars, SIdent, math::Scalar);
+    bind_typed!(bind_point, points, PIdent, math::Point);
+    bind_typed!(bind_line, lines, LIdent, math::Line);
+    bind_typed!(bind_scalar_macro, scalar_macros, SIdent, Macro<Scalar>);
+    bind_typed!(bind_point_macro, point_macros, PIdent, Macro<Point>);
+    bind_typed!(bind_line_macro, line_macros, LIdent, Macro<Line>);
+
+    /// Records the scope in which to expand the macro `id`, which was passed
+    /// as an argument from that scope.
+    pub fn bind_closure(&mut self, id: String, env: &'a Variables<'a>) {
+        self.closures.insert(id, env);
+    }
+
+    fn get_module(&self, name: &str) -> Result<&Variables<'static>, Box<Error>> {
+        let mut scope = Some(self);
+        while let Some(vars) = scope {
+            if let Some(module) = vars.modules.get(name) {
+                return Ok(module);
+            }
+            scope = vars.globals;
+        }
+        Err(errors::Error::undefined_module(name))?
+    }
+
+    /// Adds the definitions of an imported file under the name `name`. The
+    /// module's routes are also added to this scope, qualified by `name`, so
+    /// that they are laid out along with the rest of the map.
+    pub fn insert_module(&mut self, name: String, module: Variables<'static>) {
+        for (id, route) in module.routes.iter() {
+            self.routes.insert(RIdent(format!("{}::{}", name, id.0)), route.clone());
+        }
+        self.modules.insert(name, module);
+    }
 
     insert_typed!(insert_scalar, scalars, SIdent, Scalar);
     insert_typed!(insert_point, points, PIdent, Point);
     insert_typed!(insert_line, lines, LIdent, Line);
+    insert_typed!(insert_string, strings, StrIdent, Str);
 
     pub fn insert_route(&mut self, id: RIdent, route: Route) -> Result<(), Box<Error>> {
-        let route = route.eval(self)?;
+        let mut route = route.eval(self)?;
+        route.set_doc(self.doc.clone());
         self.routes.insert(id, route);
         Ok(())
     }
@@ -119,8 +360,6 @@ impl<'a> Variables<'a> {
             }
         }
         self.segments.insert(seg, SegmentBase {
-            start: seg.start,
-            end: seg.end,
             min_offset: off,
             max_offset: off,
         });
@@ -142,6 +381,124 @@ impl<'a> Variables<'a> {
         self.commands.push(cmd);
     }
 
+    /// Records where an identifier was defined. Macro names are suffixed with
+    /// `[]`.
+    pub fn record_definition(&mut self, id: String, span: Span) {
+        self.definitions.push((id, span));
+    }
+
+    /// Checks that `id` is not already defined in this scope, unless the
+    /// definition at `span` is marked `redefine`. Definitions evaluated again
+    /// by a loop do not count as redefinitions.
+    pub fn check_redefinition(&mut self,
+                              id: &str,
+                              span: Span,
+                              redefine: bool) -> Result<(), Box<Error>> {
+        let previous = self.definitions.iter()
+            .rev()
+            .find(|&(other, _)| other == id)
+            .map(|&(_, previous)| previous);
+        match previous {
+            Some(previous) if !redefine && previous != span => {
+                if self.warn_redefinitions {
+                    self.redefinitions.push((String::from(id), span, previous));
+                } else {
+                    Err(errors::Error::redefinition(id, previous))?;
+                }
+            },
+            _ => {},
+        }
+        Ok(())
+    }
+
+    /// Records a reference to a route from a `\routes` command.
+    pub fn record_route_ref(&mut self, id: &RIdent, span: Span) {
+        self.route_refs.push((format!("{}", id), span));
+    }
+
+    /// Finds definitions which are never used, routes which are drawn by more
+    /// than one `\routes` command, and redefinitions allowed by
+    /// `warn_redefinitions`, in this scope and in its modules. A module's
+    /// routes are also used if they are drawn by this scope.
+    pub fn lint(&self) -> Vec<errors::Error> {
+        let used = self.used.borrow();
+        let mut warnings = self.definitions.iter()
+            .filter(|&(id, _)| !used.contains(id))
+            .map(|&(ref id, span)| errors::Error::unused(id).at(span))
+            .collect::<Vec<_>>();
+        let mut seen = HashSet::new();
+        for &(ref id, span) in self.route_refs.iter() {
+            if !seen.insert(id) {
+                warnings.push(errors::Error::duplicate_route(id).at(span));
+            }
+        }
+        warnings.extend(self.redefinitions.iter()
+            .map(|&(ref id, span, previous)| errors::Error::redefinition(id, previous).at(span)));
+        let mut names = self.modules.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            for warning in self.modules[name].lint() {
+                let warning = warning.qualified(name);
+                if let errors::ErrorKind::Unused { ref ident } = *warning.kind() {
+                    if used.contains(ident) {
+                        continue;
+                    }
+                }
+                warnings.push(warning);
+            }
+        }
+        warnings
+    }
+
+    /// Resolves the offsets of all routes and records the segments they use.
+    ///
+    /// Blank offsets are assigned automatically if `auto_offsets` is set, and
+    /// default to 0 otherwise. If `minimize_crossings` is set, lanes are then
+    /// swapped to reduce the number of places where routes cross.
+    pub fn layout(&mut self) {
+        let (ids, mut routes) = self.sorted_routes();
+        if self.auto_offsets {
+            layout::assign_offsets(&mut routes);
+        }
+        for route in routes.iter_mut() {
+            for i in 0..route.segments().len() {
+                let off = route.offset(i).unwrap_or(0.0);
+                route.set_offset(i, off);
+            }
+        }
+        if self.minimize_crossings {
+            layout::minimize_crossings(&mut routes);
+        }
+        for (id, route) in ids.into_iter().zip(routes) {
+            for (i, &seg) in route.segments().iter().enumerate() {
+                self.insert_segment(seg, route.offset(i).unwrap());
+            }
+            self.routes.insert(id, route);
+        }
+        update_routes(&mut self.commands, &self.routes);
+    }
+
+    /// Finds the places where two routes sharing a segment cross each other.
+    ///
+    /// Must be called after `layout`.
+    pub fn crossings(&self) -> Vec<errors::Error> {
+        let (ids, routes) = self.sorted_routes();
+        layout::crossings(&routes).into_iter()
+            .map(|c| errors::Error::crossing(
+                    format!("{}", ids[c.routes.0]).as_ref(),
+                    format!("{}", ids[c.routes.1]).as_ref(),
+                    c.at,
+                    c.segments))
+            .collect()
+    }
+
+    fn sorted_routes(&self) -> (Vec<RIdent>, Vec<route::Route>) {
+        let mut ids = self.routes.keys().cloned().collect::<Vec<_>>();
+        ids.sort_by(|a, b| a.0.cmp(&b.0));
+        let routes = ids.iter().map(|id| self.routes[id].clone()).collect();
+        (ids, routes)
+    }
+
     pub fn eval_def(&mut self, def: Definition) -> Result<(), Box<Error>> {
         use self::Definition::*;
         match def {
@@ -157,16 +514,31 @@ impl<'a> Variables<'a> {
             Route(id, val) => {
                 self.insert_route(id, val)
             },
-            ScalarMacro(id, args, body) => {
-                self.scalar_macros.insert(id.clone(), Macro{id, args, body});
+            ScalarMacro(id, params, body) => {
+                self.scalar_macros.insert(id.clone(), Macro{id, params, body});
+                Ok(())
+            },
+            PointMacro(id, params, body) => {
+                self.point_macros.insert(id.clone(), Macro{id, params, body});
+                Ok(())
+            },
+            LineMacro(id, params, body) => {
+                self.line_macros.insert(id.clone(), Macro{id, params, body});
+                Ok(())
+            },
+            RouteMacro(id, params, body) => {
+                self.route_macros.insert(id.clone(), Macro{id, params, body});
                 Ok(())
             },
-            PointMacro(id, args, body) => {
-                self.point_macros.insert(id.clone(), Macro{id, args, body});
+            StopMacro(id, params, body) => {
+                self.stop_macros.insert(id.clone(), Macro{id, params, body: *body});
                 Ok(())
             },
-            LineMacro(id, args, body) => {
-                self.line_macros.insert(id.clone(), Macro{id, args, body});
+            Str(id, val) => {
+                self.insert_string(id, &val)
+            },
+            StrMacro(id, params, body) => {
+                self.string_macros.insert(id.clone(), Macro{id, params, body});
                 Ok(())
             },
         }
@@ -177,8 +549,9 @@ impl<'a> Variables<'a> {
         for s in self.style.iter() {
             writeln!(w, r#"<?xml-stylesheet type="text/css" href="{}"?>"#, s)?;
         }
-        writeln!(w, r#"<svg width="{}" height="{}""#, (self.bounds.1).0, (self.bounds.1).1)?;
-        writeln!(w, r#"viewBox="{} {}""#, self.bounds.0, self.bounds.1)?;
+        let bounds = (errors::finite(self.bounds.0)?, errors::finite(self.bounds.1)?);
+        writeln!(w, r#"<svg width="{}" height="{}""#, (bounds.1).0, (bounds.1).1)?;
+        writeln!(w, r#"viewBox="{} {}""#, bounds.0, bounds.1)?;
         writeln!(w, r#"xmlns="http://www.w3.org/2000/svg""#)?;
         writeln!(w, r#"xmlns:xlink="http://www.w3.org/1999/xlink">"#)?;
         writeln!(w, "<defs>")?;
@@ -187,17 +560,49 @@ impl<'a> Variables<'a> {
         }
         writeln!(w, "</defs>")?;
         for c in self.commands.iter() {
-            c.format_use(w, self)?;
+            c.format_use(w)?;
         }
         writeln!(w, "</svg>")?;
         Ok(())
     }
 }
 
+/// Levenshtein distance between two strings.
+pub fn distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..b.len() + 1).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == cb {
+                prev
+            } else {
+                1 + prev.min(cur).min(row[j])
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+fn update_routes(cmds: &mut [Command], routes: &HashMap<RIdent, route::Route>) {
+    for cmd in cmds.iter_mut() {
+        match *cmd {
+            Command::Group(ref mut v, _) => update_routes(v, routes),
+            Command::Route(ref mut route, ref id) => {
+                if let Some(r) = routes.get(&RIdent(id.clone())) {
+                    *route = r.clone();
+                }
+            },
+            Command::Stop(..) => {},
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 struct SegmentBase {
-    start: math::Point,
-    end: math::Point,
     min_offset: math::Scalar,
     max_offset: math::Scalar,
 }
@@ -2,6 +2,8 @@ use std::error::Error;
 
 use std::io::prelude::*;
 
+use errors;
+use errors::Span;
 use route::Route;
 use stop::Stop;
 use ast::Variables;
@@ -10,7 +12,7 @@ use ast::Variables;
 pub enum Command {
     Group(Vec<Command>, String),
     Route(Route, String),
-    Stop(Stop, String),
+    Stop(Stop, String, String, Option<String>, Span),
 }
 
 impl Command {
@@ -25,40 +27,61 @@ impl Command {
                 }
             },
             Route(ref r, ref id) => {
-                r.format_def(buf, vars, id)?;
+                r.format_def(buf, vars, &css_name(id))?;
             }
-            Stop(ref s, ref id) => {
-                s.format_def(buf, vars, id)?;
+            Stop(ref s, ref label, ref id, ref doc, span) => {
+                s.format_def(buf, vars, label, id, doc.as_ref())
+                    .map_err(|e| errors::locate(e, span))?;
             }
         }
         Ok(())
     }
-    pub fn format_use<W: Write>(&self,
-                                buf: &mut W,
-                                vars: &Variables) -> Result<(), Box<Error>> {
+    pub fn format_use<W: Write>(&self, buf: &mut W) -> Result<(), Box<Error>> {
         use self::Command::*;
         match *self {
             Group(ref v, ref s) => {
                 writeln!(buf, r#"<g class="g_{}">"#, s)?;
                 writeln!(buf, r#"<g class="bg">"#)?;
                 for ref c in v {
-                    c.format_use(buf, vars)?;
+                    c.format_use(buf)?;
                 }
                 writeln!(buf, "</g>")?;
                 writeln!(buf, r#"<g class="fg">"#)?;
                 for ref c in v {
-                    c.format_use(buf, vars)?;
+                    c.format_use(buf)?;
                 }
                 writeln!(buf, "</g>")?;
                 writeln!(buf, "</g>")?;
             },
             Route(_, ref id) => {
-                writeln!(buf, r##"<use xlink:href="#{0}" class="route r_{0}"/>"##, id)?;
+                writeln!(buf, r##"<use xlink:href="#{0}" class="route r_{0}"/>"##, css_name(id))?;
             },
-            Stop(ref s, ref id) => {
-                s.format_use(buf, vars, id)?;
+            Stop(ref s, _, ref id, ..) => {
+                s.format_use(buf, id)?;
             },
         }
         Ok(())
     }
 }
+
+/// Returns the id of a route in a form which can be used as an SVG id and in a
+/// CSS class name, with the `::` after the name of a module replaced by `__`.
+fn css_name(id: &str) -> String {
+    id.replace("::", "__")
+}
+
+/// Writes a doc comment as a `<title>`, from its first line, and a `<desc>`,
+/// from the rest.
+pub fn format_doc<W: Write>(buf: &mut W, doc: &str) -> Result<(), Box<Error>> {
+    let mut lines = doc.lines();
+    writeln!(buf, "<title>{}</title>", escape(lines.next().unwrap_or("")))?;
+    let desc = lines.collect::<Vec<_>>().join("\n");
+    if !desc.trim().is_empty() {
+        writeln!(buf, "<desc>{}</desc>", escape(desc.trim()))?;
+    }
+    Ok(())
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
@@ -1,82 +1,712 @@
 use std::error;
 use std::fmt;
+use std::io;
 
+use lalrpop_util::ParseError;
+
+use lexer::{Tok, LexError};
+use math;
 use route;
+use source::{SourceFile, SourceMap};
+
+/// A range of byte offsets into the sources of a map, as laid out by a
+/// `SourceMap`.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+}
+
+/// The kinds of errors that can occur while compiling a map.
+#[derive(Debug)]
+pub enum ErrorKind {
+    /// A string which is not a valid identifier of the given type.
+    IdentType { ident: String, type_: String },
+    /// An identifier which is not defined in any scope, along with similarly
+    /// named identifiers of the same type, and identifiers with the same name
+    /// but a different type. Macro names are suffixed with `[]`.
+    Undefined { ident: String, suggestions: Vec<String>, others: Vec<String> },
+    /// A qualified identifier whose module has not been imported.
+    UndefinedModule { name: String },
+    /// A macro called with the wrong number of arguments.
+    MacroArgs { id: String, got: usize, expected: usize },
+    /// A macro argument of the wrong type.
+    MacroArgType { macro_id: String, arg_id: String, type_: String, val: String },
+    /// A macro passed as an argument whose parameters do not match those the
+    /// macro parameter is declared with.
+    MacroSignature { macro_id: String, arg_id: String, expected: String, got: String },
+    /// Macro expansions nested more deeply than the maximum depth.
+    RecursionLimit { id: String, depth: usize },
+    /// Macro expansions nested so deeply that they would overflow the stack.
+    StackLimit { id: String, depth: usize },
+    /// A `\max_depth` which is not a whole number from 1 to the maximum.
+    MaxDepth { depth: math::Scalar, max: usize },
+    /// A named macro argument which is not one of the macro's parameters.
+    UnknownArg { macro_id: String, arg_id: String },
+    /// A macro argument given more than once.
+    DuplicateArg { macro_id: String, arg_id: String },
+    /// A macro parameter with no argument and no default value.
+    MissingArg { macro_id: String, arg_id: String },
+    /// A call to a built-in function which does not exist, along with
+    /// similarly named functions.
+    UnknownFunction { name: String, suggestions: Vec<String> },
+    /// A built-in function called with the wrong number of arguments.
+    FunctionArgs { name: String, got: usize, expected: usize },
+    /// A built-in function argument which is not of the given type.
+    FunctionArgType { name: String, index: usize, type_: String },
+    /// A built-in function whose result is not of the type expected where
+    /// it is called.
+    FunctionType { name: String, type_: String, expected: String },
+    /// A loop range with a bound which is not finite.
+    InfiniteRange { start: math::Scalar, end: math::Scalar },
+    /// A loop taking more values than the maximum.
+    LoopLimit { count: math::Scalar, max: usize },
+    /// A non-integer scalar interpolated into an identifier.
+    Interpolation { ident: String, var: String, val: math::Scalar },
+    /// A segment which is not part of any route.
+    Segment(route::Segment),
+    /// Two routes which swap sides between adjacent segments.
+    Crossing {
+        routes: (String, String),
+        at: math::Point,
+        segments: (route::Segment, route::Segment),
+    },
+    /// A definition which is never used.
+    Unused { ident: String },
+    /// A second definition of an identifier in the same scope, without
+    /// `\redefine`.
+    Redefinition { ident: String, previous: Span },
+    /// A route drawn by more than one `\routes` command.
+    DuplicateRoute { ident: String },
+    /// A syntax error.
+    Parse { details: String, expected: Vec<String> },
+    /// A `/*` comment with no closing `*/`.
+    UnterminatedComment,
+    /// A failure to read or write a file.
+    Io { path: String },
+    /// A file which includes itself, along with the chain of includes.
+    IncludeCycle { chain: Vec<String> },
+    /// A command or setting in an imported file.
+    ModuleCommand,
+    /// A definition which depends on itself, along with the chain of
+    /// identifiers leading back to it.
+    DefinitionCycle { chain: Vec<String> },
+    /// A geometric construction with no well-defined result.
+    Geometry(String),
+    /// Any other error.
+    Other(String),
+}
 
 #[derive(Debug)]
 pub struct Error {
-    details: String,
+    /// Boxed, so that results which may hold an error stay small.
+    kind: Box<ErrorKind>,
+    span: Option<Span>,
     cause: Option<Box<error::Error>>,
+    /// The macros being expanded where the error occurred, innermost first,
+    /// and where each was called.
+    expansions: Vec<(String, Span)>,
 }
 
 impl Error {
-    pub fn ident_type(ident: &str, type_: &str) -> Error {
+    pub fn new(kind: ErrorKind) -> Error {
         Error {
-            details: format!("{} is not a valid {} identifier", ident, type_),
+            kind: Box::new(kind),
+            span: None,
             cause: None,
+            expansions: Vec::new(),
         }
     }
 
-    pub fn undefined(ident: &str) -> Error {
-        Error {
-            details: format!("Variable {} not defined", ident),
-            cause: None,
-        }
+    pub fn with_cause<E: error::Error + 'static>(mut self, cause: E) -> Error {
+        self.cause = Some(Box::new(cause));
+        self
+    }
+
+    pub fn ident_type(ident: &str, type_: &str) -> Error {
+        Error::new(ErrorKind::IdentType {
+            ident: String::from(ident),
+            type_: String::from(type_),
+        })
+    }
+
+    pub fn undefined(ident: &str, suggestions: Vec<String>, others: Vec<String>) -> Error {
+        Error::new(ErrorKind::Undefined {
+            ident: String::from(ident),
+            suggestions,
+            others,
+        })
+    }
+
+    pub fn undefined_module(name: &str) -> Error {
+        Error::new(ErrorKind::UndefinedModule { name: String::from(name) })
     }
 
     pub fn macro_args(id: &str, got: usize, expected: usize) -> Error {
-        let details = format!("Incorrect number of arguments to macro {} \
-                          (got {}, expected {})",
-                          id, got, expected);
-        Error {
-            details,
-            cause: None,
-        }
+        Error::new(ErrorKind::MacroArgs {
+            id: String::from(id),
+            got,
+            expected,
+        })
     }
 
     pub fn macro_arg_type(macro_id: &str,
                           arg_id: &str,
                           type_: &str,
                           val: &str) -> Error {
-        let details = format!("Argument {} to macro {} is not a {} (got {})",
-                          arg_id, macro_id, type_, val);
-        Error {
-            details,
-            cause: None,
-        }
+        Error::new(ErrorKind::MacroArgType {
+            macro_id: String::from(macro_id),
+            arg_id: String::from(arg_id),
+            type_: String::from(type_),
+            val: String::from(val),
+        })
+    }
+
+    pub fn macro_signature(macro_id: &str, arg_id: &str, expected: &str, got: &str) -> Error {
+        Error::new(ErrorKind::MacroSignature {
+            macro_id: String::from(macro_id),
+            arg_id: String::from(arg_id),
+            expected: String::from(expected),
+            got: String::from(got),
+        })
+    }
+
+    pub fn recursion_limit(id: &str, depth: usize) -> Error {
+        Error::new(ErrorKind::RecursionLimit { id: String::from(id), depth })
+    }
+
+    pub fn stack_limit(id: &str, depth: usize) -> Error {
+        Error::new(ErrorKind::StackLimit { id: String::from(id), depth })
+    }
+
+    pub fn max_depth(depth: math::Scalar, max: usize) -> Error {
+        Error::new(ErrorKind::MaxDepth { depth, max })
+    }
+
+    pub fn unknown_arg(macro_id: &str, arg_id: &str) -> Error {
+        Error::new(ErrorKind::UnknownArg {
+            macro_id: String::from(macro_id),
+            arg_id: String::from(arg_id),
+        })
+    }
+
+    pub fn duplicate_arg(macro_id: &str, arg_id: &str) -> Error {
+        Error::new(ErrorKind::DuplicateArg {
+            macro_id: String::from(macro_id),
+            arg_id: String::from(arg_id),
+        })
+    }
+
+    pub fn missing_arg(macro_id: &str, arg_id: &str) -> Error {
+        Error::new(ErrorKind::MissingArg {
+            macro_id: String::from(macro_id),
+            arg_id: String::from(arg_id),
+        })
     }
 
     pub fn segment(seg: route::Segment) -> Error {
+        Error::new(ErrorKind::Segment(seg))
+    }
+
+    pub fn crossing(a: &str,
+                    b: &str,
+                    at: math::Point,
+                    segments: (route::Segment, route::Segment)) -> Error {
+        Error::new(ErrorKind::Crossing {
+            routes: (String::from(a), String::from(b)),
+            at,
+            segments,
+        })
+    }
+
+    pub fn unknown_function(name: &str, suggestions: Vec<String>) -> Error {
+        Error::new(ErrorKind::UnknownFunction { name: String::from(name), suggestions })
+    }
+
+    pub fn function_args(name: &str, got: usize, expected: usize) -> Error {
+        Error::new(ErrorKind::FunctionArgs { name: String::from(name), got, expected })
+    }
+
+    pub fn function_arg_type(name: &str, index: usize, type_: &str) -> Error {
+        Error::new(ErrorKind::FunctionArgType {
+            name: String::from(name),
+            index,
+            type_: String::from(type_),
+        })
+    }
+
+    pub fn function_type(name: &str, type_: &str, expected: &str) -> Error {
+        Error::new(ErrorKind::FunctionType {
+            name: String::from(name),
+            type_: String::from(type_),
+            expected: String::from(expected),
+        })
+    }
+
+    pub fn infinite_range(start: math::Scalar, end: math::Scalar) -> Error {
+        Error::new(ErrorKind::InfiniteRange { start, end })
+    }
+
+    pub fn loop_limit(count: math::Scalar, max: usize) -> Error {
+        Error::new(ErrorKind::LoopLimit { count, max })
+    }
+
+    pub fn interpolation(ident: &str, var: &str, val: math::Scalar) -> Error {
+        Error::new(ErrorKind::Interpolation {
+            ident: String::from(ident),
+            var: String::from(var),
+            val,
+        })
+    }
+
+    pub fn unused(ident: &str) -> Error {
+        Error::new(ErrorKind::Unused { ident: String::from(ident) })
+    }
+
+    pub fn redefinition(ident: &str, previous: Span) -> Error {
+        Error::new(ErrorKind::Redefinition { ident: String::from(ident), previous })
+    }
+
+    pub fn duplicate_route(ident: &str) -> Error {
+        Error::new(ErrorKind::DuplicateRoute { ident: String::from(ident) })
+    }
+
+    /// Converts a syntax error in a file starting at offset `base` into an
+    /// error. Locations in `err` are relative to the start of the file.
+    pub fn parse(err: ParseError<usize, Tok, LexError>, base: usize, text: &str) -> Error {
+        let (details, expected, span) = match err {
+            ParseError::InvalidToken { location } => {
+                let len = text[location..].chars().next().map_or(0, |c| c.len_utf8());
+                (String::from("Invalid token"), Vec::new(), Span::new(location, location + len))
+            },
+            ParseError::UnrecognizedToken { token: Some((l, Tok::Invalid(_), r)), .. } => {
+                (format!("Invalid token `{}`", &text[l..r]), Vec::new(), Span::new(l, r))
+            },
+            ParseError::UnrecognizedToken { token: Some((l, _, r)), expected } => {
+                (format!("Unexpected token `{}`", &text[l..r]), expected, Span::new(l, r))
+            },
+            ParseError::UnrecognizedToken { token: None, expected } => {
+                (String::from("Unexpected end of file"), expected, Span::new(text.len(), text.len()))
+            },
+            ParseError::ExtraToken { token: (l, _, r) } => {
+                (format!("Extra token `{}`", &text[l..r]), Vec::new(), Span::new(l, r))
+            },
+            ParseError::User { error: LexError::UnterminatedComment(location) } => {
+                let span = Span::new(base + location, base + location + 2);
+                return Error::new(ErrorKind::UnterminatedComment).at(span);
+            },
+        };
         Error {
-            details: format!("Segment {:?} not defined", seg),
+            kind: Box::new(ErrorKind::Parse { details, expected }),
+            span: Some(Span::new(base + span.start, base + span.end)),
             cause: None,
+            expansions: Vec::new(),
         }
     }
 
+    pub fn io(path: &str, err: io::Error) -> Error {
+        Error::new(ErrorKind::Io { path: String::from(path) }).with_cause(err)
+    }
+
+    pub fn module_command() -> Error {
+        Error::new(ErrorKind::ModuleCommand)
+    }
+
+    pub fn include_cycle(chain: Vec<String>) -> Error {
+        Error::new(ErrorKind::IncludeCycle { chain })
+    }
+
+    pub fn definition_cycle(chain: Vec<String>) -> Error {
+        Error::new(ErrorKind::DefinitionCycle { chain })
+    }
+
+    pub fn geometry(details: &str) -> Error {
+        Error::new(ErrorKind::Geometry(String::from(details)))
+    }
+
+    pub fn parallel(a: &str, b: &str) -> Error {
+        Error::geometry(format!("Lines {} and {} are parallel, and do not intersect",
+                                a, b).as_ref())
+    }
+
     pub fn from_val<T: fmt::Debug>(val: T) -> Error {
-        Error {
-            details: format!("{:?}", val),
-            cause: None,
-        }
+        Error::new(ErrorKind::Other(format!("{:?}", val)))
     }
 
     pub fn from_str(details: &str) -> Error {
-        Error {
-            details: String::from(details),
-            cause: None,
+        Error::new(ErrorKind::Other(String::from(details)))
+    }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
+
+    pub fn at(mut self, span: Span) -> Error {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn span(&self) -> Option<Span> {
+        self.span
+    }
+
+    /// Qualifies the identifiers in the error by the module they are from.
+    pub fn qualified(mut self, module: &str) -> Error {
+        let q = |id: &String| qualify_ident(id, module);
+        match *self.kind {
+            ErrorKind::Undefined { ref mut ident, ref mut suggestions, ref mut others } => {
+                *ident = q(ident);
+                *suggestions = suggestions.iter().map(&q).collect();
+                *others = others.iter().map(&q).collect();
+            },
+            ErrorKind::Unused { ref mut ident } |
+            ErrorKind::Redefinition { ref mut ident, .. } |
+            ErrorKind::DuplicateRoute { ref mut ident } => *ident = q(ident),
+            _ => {},
+        }
+        self
+    }
+
+    /// Formats the error along with the location it refers to in `sources`,
+    /// and an excerpt of the offending line. If the error is in a file loaded
+    /// by `\include`, the chain of includes is listed after the excerpt.
+    pub fn render(&self, level: &str, sources: &SourceMap) -> String {
+        let span = match self.span {
+            Some(span) => span,
+            None => return format!("{}: {}", level, self),
+        };
+        let file = sources.get(sources.find(span.start));
+        let source = file.text.as_str();
+        let start = (span.start - file.base).min(source.len());
+        let end = span.end - file.base;
+        let (line_no, col, line_start) = locate_in(file, span.start);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let line = &source[line_start..line_end];
+        let width = source[start..end.min(line_end).max(start)].chars().count().max(1);
+        let gutter = " ".repeat(line_no.to_string().len());
+        let mut out = format!("{level}: {details}\n\
+                               {gutter}--> {file}:{line_no}:{col}\n\
+                               {gutter} |\n\
+                               {line_no} | {line}\n\
+                               {gutter} | {pad}{carets}",
+                              level = level,
+                              details = self,
+                              gutter = gutter,
+                              file = file.name,
+                              line_no = line_no,
+                              col = col,
+                              line = line,
+                              pad = " ".repeat(col - 1),
+                              carets = "^".repeat(width));
+        if let ErrorKind::Redefinition { previous, .. } = *self.kind {
+            let file = sources.get(sources.find(previous.start));
+            let (line_no, col, _) = locate_in(file, previous.start);
+            out.push_str(format!("\n{} = note: previously defined at {}:{}:{}",
+                                 gutter, file.name, line_no, col).as_ref());
+        }
+        // show only the outermost and innermost expansions of a deep recursion
+        let n = self.expansions.len();
+        for (i, &(ref id, span)) in self.expansions.iter().enumerate() {
+            if n > 10 && i >= 5 && i < n - 5 {
+                if i == 5 {
+                    out.push_str(format!("\n{} = note: ... {} more expansions",
+                                         gutter, n - 10).as_ref());
+                }
+                continue;
+            }
+            let file = sources.get(sources.find(span.start));
+            let (line_no, col, _) = locate_in(file, span.start);
+            out.push_str(format!("\n{} = note: in expansion of {} at {}:{}:{}",
+                                 gutter, id, file.name, line_no, col).as_ref());
         }
+        let mut from = file.included_from;
+        while let Some(span) = from {
+            let file = sources.get(sources.find(span.start));
+            let (line_no, col, _) = locate_in(file, span.start);
+            out.push_str(format!("\n{} = note: included from {}:{}:{}",
+                                 gutter, file.name, line_no, col).as_ref());
+            from = file.included_from;
+        }
+        out
+    }
+}
+
+/// Checks that a point about to be written to the output has finite
+/// coordinates.
+pub fn finite(p: math::Point) -> Result<math::Point, Error> {
+    if p.is_finite() {
+        Ok(p)
+    } else {
+        Err(Error::geometry(format!("Point ({}) has non-finite coordinates", p).as_ref()))
+    }
+}
+
+/// Attaches a location to an error, unless it already has a more specific one.
+pub fn locate(err: Box<error::Error>, span: Span) -> Box<error::Error> {
+    match err.downcast::<Error>() {
+        Ok(mut err) => {
+            if err.span.is_none() {
+                err.span = Some(span);
+            }
+            err
+        },
+        Err(err) => err,
+    }
+}
+
+/// Finds the line number, column, and offset of the start of the line of
+/// `offset` within `file`.
+fn locate_in(file: &SourceFile, offset: usize) -> (usize, usize, usize) {
+    let source = file.text.as_str();
+    let start = (offset - file.base).min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_no = source[..start].matches('\n').count() + 1;
+    let col = source[line_start..start].chars().count() + 1;
+    (line_no, col, line_start)
+}
+
+/// Records that an error occurred in the expansion of the macro `id` called
+/// at `span`.
+pub fn expansion(err: Box<error::Error>, id: &str, span: Span) -> Box<error::Error> {
+    match err.downcast::<Error>() {
+        Ok(mut err) => {
+            if err.span.is_none() {
+                err.span = Some(span);
+            } else {
+                err.expansions.push((String::from(id), span));
+            }
+            err
+        },
+        Err(err) => err,
+    }
+}
+
+/// Removes the errors which repeat an earlier one at the same location, such
+/// as those from each iteration of a loop.
+pub fn dedup(errs: Vec<Box<error::Error>>) -> Vec<Box<error::Error>> {
+    let mut seen = Vec::new();
+    errs.into_iter().filter(|err| {
+        let span = err.downcast_ref::<Error>().and_then(|e| e.span());
+        let key = (span, format!("{}", err));
+        if seen.contains(&key) {
+            false
+        } else {
+            seen.push(key);
+            true
+        }
+    }).collect()
+}
+
+/// Qualifies the identifiers in an error from looking up a name in the
+/// module `module`.
+pub fn qualify(err: Box<error::Error>, module: &str) -> Box<error::Error> {
+    match err.downcast::<Error>() {
+        Ok(err) => Box::new(err.qualified(module)),
+        Err(err) => err,
+    }
+}
+
+/// Qualifies an identifier from the module `module`.
+pub fn qualify_ident(ident: &str, module: &str) -> String {
+    format!("{}{}::{}", &ident[..1], module, &ident[1..])
+}
+
+fn or_list(items: &[String]) -> String {
+    match items.len() {
+        0 => String::new(),
+        1 => items[0].clone(),
+        n => format!("{} or {}", items[..n - 1].join(", "), items[n - 1]),
+    }
+}
+
+/// Describes the type of an identifier from its sigil.
+fn ident_kind(ident: &str) -> String {
+    let kind = match ident.chars().next() {
+        Some('$') => "scalar",
+        Some('@') => "point",
+        Some('!') => "line",
+        Some('~') => "route",
+        Some('%') => "stop",
+        Some('#') => "string",
+        _ => "identifier",
+    };
+    if ident.ends_with("[]") {
+        format!("{} macro", kind)
+    } else {
+        String::from(kind)
+    }
+}
+
+fn expected_list(expected: &[String]) -> String {
+    if expected.is_empty() {
+        String::new()
+    } else {
+        format!(", expected one of {}", expected.join(", "))
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.details)
+        use self::ErrorKind::*;
+        match *self.kind {
+            IdentType { ref ident, ref type_ } => {
+                write!(f, "{} is not a valid {} identifier", ident, type_)
+            },
+            Undefined { ref ident, ref suggestions, ref others } => {
+                write!(f, "Variable {} not defined", ident)?;
+                if !suggestions.is_empty() {
+                    write!(f, "; did you mean {}?", or_list(suggestions))?;
+                }
+                for other in others {
+                    write!(f, "; note that {} is a {}", other, ident_kind(other))?;
+                }
+                Ok(())
+            },
+            UndefinedModule { ref name } => write!(f, "Module {} not imported", name),
+            MacroArgs { ref id, got, expected } => {
+                write!(f, "Incorrect number of arguments to macro {} \
+                       (got {}, expected {})",
+                       id, got, expected)
+            },
+            MacroArgType { ref macro_id, ref arg_id, ref type_, ref val } => {
+                write!(f, "Argument {} to macro {} is not a {} (got {})",
+                       arg_id, macro_id, type_, val)
+            },
+            MacroSignature { ref macro_id, ref arg_id, ref expected, ref got } => {
+                write!(f, "Argument {} to macro {} must be a macro like {} (got {})",
+                       arg_id, macro_id, expected, got)
+            },
+            RecursionLimit { ref id, depth } => {
+                write!(f, "Expansion of macro {} exceeds the maximum depth of {}; \
+                       use \\max_depth to raise it", id, depth)
+            },
+            StackLimit { ref id, depth } => {
+                write!(f, "Expansion of macro {} at depth {} would overflow the stack", id, depth)
+            },
+            MaxDepth { depth, max } => {
+                write!(f, "Maximum depth must be a whole number from 1 to {} (got {})", max, depth)
+            },
+            UnknownArg { ref macro_id, ref arg_id } => {
+                write!(f, "Macro {} has no argument {}", macro_id, arg_id)
+            },
+            DuplicateArg { ref macro_id, ref arg_id } => {
+                write!(f, "Argument {} to macro {} is given more than once", arg_id, macro_id)
+            },
+            MissingArg { ref macro_id, ref arg_id } => {
+                write!(f, "Missing argument {} to macro {}", arg_id, macro_id)
+            },
+            UnknownFunction { ref name, ref suggestions } => {
+                write!(f, "Unknown function {}", name)?;
+                if !suggestions.is_empty() {
+                    write!(f, "; did you mean {}?", or_list(suggestions))?;
+                }
+                Ok(())
+            },
+            FunctionArgs { ref name, got, expected } => {
+                write!(f, "Incorrect number of arguments to function {} \
+                       (got {}, expected {})",
+                       name, got, expected)
+            },
+            FunctionArgType { ref name, index, ref type_ } => {
+                write!(f, "Argument {} to function {} must be a {}", index, name, type_)
+            },
+            FunctionType { ref name, ref type_, ref expected } => {
+                write!(f, "Function {} returns a {}, not a {}", name, type_, expected)
+            },
+            InfiniteRange { start, end } => {
+                write!(f, "Range {}..{} does not have finite bounds", start, end)
+            },
+            LoopLimit { count, max } => {
+                write!(f, "Loop takes {} values, more than the maximum of {}", count, max)
+            },
+            Interpolation { ref ident, ref var, val } => {
+                write!(f, "Cannot interpolate {} into identifier {}: {} is not an integer",
+                       var, ident, val)
+            },
+            Segment(seg) => write!(f, "Segment ({}) - ({}) not defined", seg.start, seg.end),
+            Crossing { ref routes, at, segments } => {
+                write!(f, "Routes {} and {} cross at ({}), between \
+                       segments ({}) - ({}) and ({}) - ({})",
+                       routes.0, routes.1, at,
+                       segments.0.start, segments.0.end,
+                       segments.1.start, segments.1.end)
+            },
+            Parse { ref details, ref expected } => {
+                write!(f, "{}{}", details, expected_list(expected))
+            },
+            UnterminatedComment => write!(f, "Unterminated block comment"),
+            Io { ref path } => {
+                write!(f, "Could not access {}", path)?;
+                if let Some(ref cause) = self.cause {
+                    write!(f, ": {}", cause)?;
+                }
+                Ok(())
+            },
+            IncludeCycle { ref chain } => {
+                write!(f, "Include cycle: {}", chain.join(" includes "))
+            },
+            DefinitionCycle { ref chain } => {
+                write!(f, "Definition cycle: {}", chain.join(" depends on "))
+            },
+            ModuleCommand => {
+                write!(f, "Imported files can only contain definitions; use \\include instead")
+            },
+            Unused { ref ident } => write!(f, "Unused {} {}", ident_kind(ident), ident),
+            Redefinition { ref ident, .. } => {
+                write!(f, "Redefinition of {} {} without \\redefine", ident_kind(ident), ident)
+            },
+            DuplicateRoute { ref ident } => {
+                write!(f, "Route {} is drawn by more than one \\routes command", ident)
+            },
+            Geometry(ref details) => write!(f, "{}", details),
+            Other(ref details) => write!(f, "{}", details),
+        }
     }
 }
 
 impl error::Error for Error {
     fn description(&self) -> &str {
-        &self.details
+        use self::ErrorKind::*;
+        match *self.kind {
+            IdentType { .. } => "invalid identifier",
+            Undefined { .. } => "undefined variable",
+            UndefinedModule { .. } => "undefined module",
+            MacroArgs { .. } => "wrong number of macro arguments",
+            MacroArgType { .. } => "wrong type of macro argument",
+            MacroSignature { .. } => "wrong signature of macro argument",
+            RecursionLimit { .. } => "macro expansion too deep",
+            StackLimit { .. } => "macro expansion too deep",
+            MaxDepth { .. } => "invalid maximum depth",
+            UnknownArg { .. } => "unknown macro argument",
+            DuplicateArg { .. } => "duplicate macro argument",
+            MissingArg { .. } => "missing macro argument",
+            UnknownFunction { .. } => "unknown function",
+            FunctionArgs { .. } => "wrong number of function arguments",
+            FunctionArgType { .. } => "wrong type of function argument",
+            FunctionType { .. } => "mismatched function result",
+            InfiniteRange { .. } => "infinite range",
+            LoopLimit { .. } => "loop too long",
+            Interpolation { .. } => "non-integer interpolation",
+            Segment(_) => "undefined segment",
+            Crossing { .. } => "routes cross",
+            Unused { .. } => "unused definition",
+            Redefinition { .. } => "redefinition",
+            DuplicateRoute { .. } => "route drawn more than once",
+            Parse { .. } => "syntax error",
+            UnterminatedComment => "unterminated block comment",
+            Io { .. } => "IO error",
+            IncludeCycle { .. } => "include cycle",
+            ModuleCommand => "command in imported file",
+            DefinitionCycle { .. } => "definition cycle",
+            Geometry(_) => "degenerate geometry",
+            Other(ref details) => details,
+        }
     }
 
     fn cause(&self) -> Option<&error::Error> {
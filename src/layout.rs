@@ -0,0 +1,586 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use math::{mod_tau, Point, Scalar, PI, TAU};
+use route::{Route, Segment};
+
+/// A single traversal of a segment by a route.
+#[derive(Clone, Copy, Debug)]
+struct Traversal {
+    /// Index of the route.
+    route: usize,
+    /// Index of the segment within the route.
+    index: usize,
+    /// Whether the route runs against the canonical direction of the segment.
+    reversed: bool,
+}
+
+/// Returns the canonical orientation of a segment, and whether `seg` runs
+/// against it.
+pub fn canonical(seg: Segment) -> (Segment, bool) {
+    match seg.start.cmp(seg.end) {
+        (Ordering::Greater, _) | (Ordering::Equal, Ordering::Greater) => (seg.reverse(), true),
+        _ => (seg, false),
+    }
+}
+
+/// Assigns offsets to every segment of `routes` which was left blank.
+///
+/// On each segment, routes with blank offsets are sorted so that they do not
+/// need to cross each other where they split, and given consecutive lanes. If
+/// some routes on the segment have explicit offsets, the automatic lanes are
+/// stacked beyond the largest of them. Otherwise the lanes are lined up with
+/// those a route already has on a neighbouring segment, so that it keeps its
+/// lane along straight runs and through corners, and are centered on the
+/// segment if there are none. Segments are laid out busiest first, spreading
+/// out along the routes from there.
+pub fn assign_offsets(routes: &mut [Route]) {
+    let mut pending = Vec::new();
+    for (seg, traversals) in shared_segments(routes) {
+        let (fixed, mut auto): (Vec<_>, Vec<_>) = traversals.into_iter()
+            .partition(|t| routes[t.route].offset(t.index).is_some());
+        if auto.is_empty() {
+            continue;
+        }
+        sort(&mut auto, |a, b| compare(routes, a, b));
+        pending.push((seg, fixed, auto));
+    }
+    pending.sort_by(|a, b| cmp_segments(a.0, b.0));
+    while !pending.is_empty() {
+        let next = (0..pending.len()).rev()
+            .max_by_key(|&i| {
+                let (_, ref fixed, ref auto) = pending[i];
+                let joined = auto.iter().any(|&t| preferred_lane(routes, t).is_some());
+                (joined, fixed.len() + auto.len())
+            })
+            .unwrap();
+        let (_, fixed, auto) = pending.remove(next);
+        let max_fixed = fixed.iter()
+            .map(|&t| lane(routes, t))
+            .fold(None, |m: Option<Scalar>, o| Some(m.map_or(o, |m| m.max(o))));
+        let first = match max_fixed {
+            Some(m) => m + 1.0,
+            None => {
+                let preferred = auto.iter().enumerate()
+                    .filter_map(|(k, &t)| preferred_lane(routes, t)
+                                .map(|(l, straight)| (l - k as Scalar, straight)))
+                    .collect::<Vec<_>>();
+                preferred.iter().find(|p| p.1)
+                    .or_else(|| preferred.first())
+                    .map_or(-(auto.len() as Scalar - 1.0) / 2.0, |p| p.0)
+            },
+        };
+        for (k, t) in auto.into_iter().enumerate() {
+            let lane = first + k as Scalar;
+            routes[t.route].set_offset(t.index, orient(lane, t.reversed));
+        }
+    }
+}
+
+/// Returns the lane, in the canonical direction of its segment, which a
+/// traversal would keep from a neighbouring segment of its route which already
+/// has an offset, and whether the route runs straight on from there. A
+/// neighbour the route runs straight on from is preferred.
+fn preferred_lane(routes: &[Route], t: Traversal) -> Option<(Scalar, bool)> {
+    let route = &routes[t.route];
+    let seg = route.segments()[t.index];
+    let mut found = None;
+    for &forwards in [true, false].iter() {
+        let cur = if forwards { seg } else { seg.reverse() };
+        if let Some((j, next)) = step(route, t.index, forwards) {
+            if let Some(off) = route.offset(j) {
+                let lane = orient(off, t.reversed);
+                if straight(cur, next) {
+                    return Some((lane, true));
+                }
+                found = found.or(Some((lane, false)));
+            }
+        }
+    }
+    found
+}
+
+/// Swaps the lanes of pairs of routes wherever doing so reduces the total
+/// number of crossings.
+///
+/// All offsets must have been assigned. Each swap exchanges the lanes of two
+/// routes along the whole stretch of segments they share, so the routes keep
+/// their order within the stretch. A swap only changes the crossings at the
+/// nodes along the stretch, so only those are counted. Swaps which would make
+/// a route change lanes where it runs straight on at the ends of the stretch
+/// are not made.
+pub fn minimize_crossings(routes: &mut [Route]) {
+    let nodes = nodes(routes);
+    let mut shared = shared_segments(routes).into_iter().collect::<Vec<_>>();
+    shared.sort_by(|a, b| cmp_segments(a.0, b.0));
+    let mut improved = !crossings(routes).is_empty();
+    while improved {
+        improved = false;
+        for (_, traversals) in shared.iter() {
+            for (k, &a) in traversals.iter().enumerate() {
+                for &b in traversals[k + 1..].iter() {
+                    if a.route == b.route || lane(routes, a) == lane(routes, b) {
+                        continue;
+                    }
+                    let pairs = stretch(routes, a, b);
+                    let at = stretch_nodes(routes, &pairs);
+                    let crossings = count_at(routes, &nodes, &at);
+                    let jumps = jumps_at(routes, &nodes, &at);
+                    swap_lanes(routes, &pairs);
+                    if count_at(routes, &nodes, &at) < crossings
+                        && jumps_at(routes, &nodes, &at) <= jumps {
+                        improved = true;
+                    } else {
+                        swap_lanes(routes, &pairs);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A place where two routes swap sides between adjacent segments.
+#[derive(Clone, Debug)]
+pub struct Crossing {
+    /// Indices of the two routes.
+    pub routes: (usize, usize),
+    /// The point where the routes cross.
+    pub at: Point,
+    /// The segments of the first route on either side of the crossing.
+    pub segments: (Segment, Segment),
+}
+
+/// Finds every place where two routes which share a segment cross each other
+/// at one of its ends.
+///
+/// All offsets must have been assigned.
+pub fn crossings(routes: &[Route]) -> Vec<Crossing> {
+    let mut found = Vec::new();
+    for (at, chords) in nodes(routes) {
+        crossings_at(routes, at, &chords, &mut found);
+    }
+    found.sort_by(|a, b| a.routes.cmp(&b.routes)
+                  .then_with(|| cmp_points(a.at, b.at)));
+    found
+}
+
+/// Finds the nodes where routes continue from one segment to the next, along
+/// with the route and the index of the segment leaving the node for each.
+fn nodes(routes: &[Route]) -> HashMap<Point, Vec<(usize, usize)>> {
+    let mut nodes = HashMap::<Point, Vec<(usize, usize)>>::new();
+    for (r, route) in routes.iter().enumerate() {
+        let segs = route.segments();
+        for i in 1..segs.len() {
+            if segs[i - 1].end == segs[i].start {
+                nodes.entry(segs[i].start).or_default().push((r, i));
+            }
+        }
+    }
+    nodes
+}
+
+/// Finds the crossings at the node `at`, through which `chords` pass.
+fn crossings_at(routes: &[Route],
+                at: Point,
+                chords: &[(usize, usize)],
+                found: &mut Vec<Crossing>) {
+    for (k, &(ra, ia)) in chords.iter().enumerate() {
+        for &(rb, ib) in chords[k + 1..].iter() {
+            if ra == rb {
+                continue;
+            }
+            let (a, b) = (&routes[ra], &routes[rb]);
+            let (a_in, a_out) = (a.segments()[ia - 1], a.segments()[ia]);
+            let (b_in, b_out) = (b.segments()[ib - 1], b.segments()[ib]);
+            let shares = [a_in, a_out].iter().any(|&s| {
+                let s = canonical(s).0;
+                s == canonical(b_in).0 || s == canonical(b_out).0
+            });
+            if !shares {
+                continue;
+            }
+            let a_ports = (port(a_in.reverse(), -a.offset(ia - 1).unwrap()),
+                           port(a_out, a.offset(ia).unwrap()));
+            let b_ports = (port(b_in.reverse(), -b.offset(ib - 1).unwrap()),
+                           port(b_out, b.offset(ib).unwrap()));
+            if interleaved(a_ports, b_ports) {
+                found.push(Crossing {
+                    routes: (ra, rb),
+                    at,
+                    segments: (a_in, a_out),
+                });
+            }
+        }
+    }
+}
+
+/// Counts the crossings at the nodes `at`.
+fn count_at(routes: &[Route], nodes: &HashMap<Point, Vec<(usize, usize)>>, at: &[Point]) -> usize {
+    let mut found = Vec::new();
+    for &p in at {
+        if let Some(chords) = nodes.get(&p) {
+            crossings_at(routes, p, chords, &mut found);
+        }
+    }
+    found.len()
+}
+
+/// Counts the places at the nodes `at` where a route changes lanes while
+/// running straight on.
+fn jumps_at(routes: &[Route], nodes: &HashMap<Point, Vec<(usize, usize)>>, at: &[Point]) -> usize {
+    at.iter()
+        .filter_map(|p| nodes.get(p))
+        .flat_map(|chords| chords.iter())
+        .filter(|&&(r, i)| {
+            let route = &routes[r];
+            let segs = route.segments();
+            straight(segs[i - 1], segs[i]) && route.offset(i - 1) != route.offset(i)
+        })
+        .count()
+}
+
+/// Returns the ends of the segments making up a stretch.
+fn stretch_nodes(routes: &[Route], pairs: &[(Traversal, Traversal)]) -> Vec<Point> {
+    let mut at = Vec::new();
+    for &(a, b) in pairs {
+        for &t in [a, b].iter() {
+            let seg = routes[t.route].segments()[t.index];
+            for &p in [seg.start, seg.end].iter() {
+                if !at.contains(&p) {
+                    at.push(p);
+                }
+            }
+        }
+    }
+    at
+}
+
+/// Groups the segments of all routes by their canonical orientation.
+fn shared_segments(routes: &[Route]) -> HashMap<Segment, Vec<Traversal>> {
+    let mut shared = HashMap::<Segment, Vec<Traversal>>::new();
+    for (r, route) in routes.iter().enumerate() {
+        for (index, &seg) in route.segments().iter().enumerate() {
+            let (key, reversed) = canonical(seg);
+            shared.entry(key).or_default().push(Traversal {
+                route: r,
+                index,
+                reversed,
+            });
+        }
+    }
+    shared
+}
+
+/// Returns the offset of a traversal, in the canonical direction of its
+/// segment.
+fn lane(routes: &[Route], t: Traversal) -> Scalar {
+    orient(routes[t.route].offset(t.index).unwrap(), t.reversed)
+}
+
+/// Returns the pairs of traversals making up the stretch of segments shared by
+/// two traversals of the same segment.
+fn stretch(routes: &[Route], a: Traversal, b: Traversal) -> Vec<(Traversal, Traversal)> {
+    let mut pairs = vec![(a, b)];
+    for &forwards in [true, false].iter() {
+        let (ra, rb) = (&routes[a.route], &routes[b.route]);
+        let (fa, fb) = (forwards != a.reversed, forwards != b.reversed);
+        let (mut ia, mut ib) = (a.index, b.index);
+        for _ in 0..(ra.segments().len() + rb.segments().len()) {
+            match (step(ra, ia, fa), step(rb, ib, fb)) {
+                (Some((ja, sa)), Some((jb, sb))) if sa == sb => {
+                    ia = ja;
+                    ib = jb;
+                    pairs.push((traversal(routes, a.route, ia), traversal(routes, b.route, ib)));
+                },
+                _ => break,
+            }
+        }
+    }
+    pairs
+}
+
+fn traversal(routes: &[Route], route: usize, index: usize) -> Traversal {
+    Traversal {
+        route,
+        index,
+        reversed: canonical(routes[route].segments()[index]).1,
+    }
+}
+
+fn swap_lanes(routes: &mut [Route], pairs: &[(Traversal, Traversal)]) {
+    for &(a, b) in pairs {
+        let (la, lb) = (lane(routes, a), lane(routes, b));
+        routes[a.route].set_offset(a.index, orient(lb, a.reversed));
+        routes[b.route].set_offset(b.index, orient(la, b.reversed));
+    }
+}
+
+/// The position of a route where it leaves a node along `seg`: the angle of
+/// the segment, and the offset of the route within it, negated so that ports
+/// sort in order of increasing angle.
+fn port(seg: Segment, offset: Scalar) -> (Scalar, Scalar) {
+    let Point(dx, dy) = seg.dir();
+    (mod_tau(dy.atan2(dx)), -offset)
+}
+
+fn cmp_port(a: (Scalar, Scalar), b: (Scalar, Scalar)) -> Ordering {
+    if (a.0 - b.0).abs() < 1e-9 {
+        a.1.partial_cmp(&b.1).unwrap_or(Ordering::Equal)
+    } else {
+        a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal)
+    }
+}
+
+/// Returns whether the paths through a node between two pairs of ports cross.
+fn interleaved(a: ((Scalar, Scalar), (Scalar, Scalar)),
+               b: ((Scalar, Scalar), (Scalar, Scalar))) -> bool {
+    let (lo, hi) = match cmp_port(a.0, a.1) {
+        Ordering::Less => (a.0, a.1),
+        Ordering::Greater => (a.1, a.0),
+        Ordering::Equal => return false,
+    };
+    let inside = |p| match (cmp_port(lo, p), cmp_port(p, hi)) {
+        (Ordering::Less, Ordering::Less) => Some(true),
+        (Ordering::Equal, _) | (_, Ordering::Equal) => None,
+        _ => Some(false),
+    };
+    match (inside(b.0), inside(b.1)) {
+        (Some(x), Some(y)) => x != y,
+        _ => false,
+    }
+}
+
+fn cmp_points(a: Point, b: Point) -> Ordering {
+    match a.cmp(b) {
+        (Ordering::Equal, y) => y,
+        (x, _) => x,
+    }
+}
+
+fn cmp_segments(a: Segment, b: Segment) -> Ordering {
+    cmp_points(a.start, b.start).then_with(|| cmp_points(a.end, b.end))
+}
+
+/// Converts an offset between the canonical direction of a segment and the
+/// direction of a traversal.
+fn orient(offset: Scalar, reversed: bool) -> Scalar {
+    if reversed { -offset } else { offset }
+}
+
+/// Orders two traversals of the same segment by which side of the segment
+/// they should be on, with `Greater` meaning further towards the positive
+/// offset.
+fn compare(routes: &[Route], a: Traversal, b: Traversal) -> Ordering {
+    diverge(routes, a, b, true)
+        .then_with(|| diverge(routes, a, b, false).reverse())
+        .then_with(|| {
+            if a.route == b.route {
+                return a.index.cmp(&b.index);
+            }
+            // keep the first route on its own positive side, so that the
+            // order stays the same along any stretch the routes share
+            let (first, side) = if a.route < b.route {
+                (a, Ordering::Greater)
+            } else {
+                (b, Ordering::Less)
+            };
+            if first.reversed { side.reverse() } else { side }
+        })
+}
+
+/// Follows two traversals of the same segment until they split, and returns
+/// which one turns further towards the positive side of the direction of
+/// travel.
+///
+/// Travel is along the canonical direction of the segment if `forwards`, and
+/// against it otherwise. Returns `Equal` if either route ends before they
+/// split.
+fn diverge(routes: &[Route], a: Traversal, b: Traversal, forwards: bool) -> Ordering {
+    let (ra, rb) = (&routes[a.route], &routes[b.route]);
+    let (fa, fb) = (forwards != a.reversed, forwards != b.reversed);
+    let (mut ia, mut ib) = (a.index, b.index);
+    let mut cur = ra.segments()[ia];
+    if !fa {
+        cur = cur.reverse();
+    }
+    for _ in 0..(ra.segments().len() + rb.segments().len()) {
+        match (step(ra, ia, fa), step(rb, ib, fb)) {
+            (Some((ja, sa)), Some((jb, sb))) => {
+                if sa == sb {
+                    ia = ja;
+                    ib = jb;
+                    cur = sa;
+                    continue;
+                }
+                let ta = turn(cur.dir(), sa.dir());
+                let tb = turn(cur.dir(), sb.dir());
+                if (ta - tb).abs() < 1e-9 {
+                    return Ordering::Equal;
+                }
+                return if ta > tb { Ordering::Greater } else { Ordering::Less };
+            },
+            _ => return Ordering::Equal,
+        }
+    }
+    Ordering::Equal
+}
+
+/// Returns the segment following the `index`th segment of `route`, oriented in
+/// the direction of travel, or `None` if the route ends or jumps there.
+fn step(route: &Route, index: usize, forwards: bool) -> Option<(usize, Segment)> {
+    let segs = route.segments();
+    if forwards {
+        if index + 1 < segs.len() && segs[index].end == segs[index + 1].start {
+            return Some((index + 1, segs[index + 1]));
+        }
+    } else if index > 0 && segs[index - 1].end == segs[index].start {
+        return Some((index - 1, segs[index - 1].reverse()));
+    }
+    None
+}
+
+/// Returns the signed angle turned going from direction `a` to direction `b`,
+/// which is positive when turning towards the positive offset side.
+fn turn(a: Point, b: Point) -> Scalar {
+    let theta = a.angle(b);
+    if theta > PI { theta - TAU } else { theta }
+}
+
+/// Returns whether `b` continues straight on from `a`.
+fn straight(a: Segment, b: Segment) -> bool {
+    turn(a.dir(), b.dir()).abs() < 1e-3
+}
+
+/// Insertion sort, which unlike the standard library sorts tolerates an
+/// ordering that is not total.
+fn sort<T: Copy, F: Fn(T, T) -> Ordering>(v: &mut [T], cmp: F) {
+    for i in 1..v.len() {
+        let mut j = i;
+        while j > 0 && cmp(v[j - 1], v[j]) == Ordering::Greater {
+            v.swap(j - 1, j);
+            j -= 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use math::{Point, Scalar};
+    use route::{Route, Segment};
+    use super::*;
+
+    /// Builds a route through `points`, with the given offsets on each segment.
+    fn route(points: &[(Scalar, Scalar)], offsets: &[Option<Scalar>]) -> Route {
+        let mut route = Route::new();
+        for (i, pair) in points.windows(2).enumerate() {
+            let seg = Segment {
+                start: Point(pair[0].0, pair[0].1),
+                end: Point(pair[1].0, pair[1].1),
+            };
+            route.push(seg, offsets[i]);
+        }
+        route
+    }
+
+    fn auto(points: &[(Scalar, Scalar)]) -> Route {
+        route(points, &vec![None; points.len() - 1])
+    }
+
+    fn offsets(route: &Route) -> Vec<Scalar> {
+        (0..route.segments().len()).map(|i| route.offset(i).unwrap()).collect()
+    }
+
+    #[test]
+    fn lanes_centered_on_shared_segments() {
+        let mut routes = vec![auto(&[(0., 0.), (10., 0.)]),
+                              auto(&[(0., 0.), (10., 0.)])];
+        assign_offsets(&mut routes);
+        let mut lanes = vec![offsets(&routes[0])[0], offsets(&routes[1])[0]];
+        lanes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(lanes, vec![-0.5, 0.5]);
+    }
+
+    #[test]
+    fn lanes_kept_on_straight_runs() {
+        let mut routes = vec![auto(&[(0., 0.), (10., 0.), (20., 0.)]),
+                              auto(&[(0., 0.), (10., 0.), (10., 10.)])];
+        assign_offsets(&mut routes);
+        let (a, b) = (offsets(&routes[0]), offsets(&routes[1]));
+        assert_eq!(a[0], a[1]);
+        assert_ne!(a[0], b[0]);
+    }
+
+    #[test]
+    fn lanes_kept_through_corners() {
+        let mut routes = vec![auto(&[(0., 0.), (10., 0.), (10., 10.)]),
+                              auto(&[(0., 0.), (10., 0.), (10., 10.)]),
+                              auto(&[(10., 0.), (10., 10.), (10., 20.)])];
+        assign_offsets(&mut routes);
+        for route in routes.iter() {
+            let offsets = offsets(route);
+            assert_eq!(offsets[0], offsets[1]);
+        }
+        assert!(crossings(&routes).is_empty());
+    }
+
+    #[test]
+    fn lanes_stacked_beyond_fixed_offsets() {
+        let mut routes = vec![route(&[(0., 0.), (10., 0.)], &[Some(1.)]),
+                              auto(&[(0., 0.), (10., 0.)]),
+                              auto(&[(10., 0.), (0., 0.)])];
+        assign_offsets(&mut routes);
+        let mut lanes = vec![offsets(&routes[1])[0], -offsets(&routes[2])[0]];
+        lanes.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(lanes, vec![2., 3.]);
+    }
+
+    #[test]
+    fn crossings_found_where_routes_swap_sides() {
+        let mut routes = vec![auto(&[(0., 0.), (10., 0.), (10., -10.)]),
+                              auto(&[(0., 0.), (10., 0.), (10., 10.)])];
+        assign_offsets(&mut routes);
+        assert!(crossings(&routes).is_empty());
+        for route in routes.iter_mut() {
+            let off = route.offset(0).unwrap();
+            route.set_offset(0, -off);
+        }
+        let found = crossings(&routes);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].routes, (0, 1));
+        assert_eq!(found[0].at, Point(10., 0.));
+    }
+
+    #[test]
+    fn crossing_lanes_swapped() {
+        let mut routes = vec![route(&[(0., 0.), (10., 0.), (10., -10.)], &[Some(-1.), Some(0.)]),
+                              route(&[(0., 0.), (10., 0.), (10., 10.)], &[Some(1.), Some(0.)])];
+        assert_eq!(crossings(&routes).len(), 1);
+        minimize_crossings(&mut routes);
+        assert!(crossings(&routes).is_empty());
+        assert_eq!(offsets(&routes[0]), vec![1., 0.]);
+        assert_eq!(offsets(&routes[1]), vec![-1., 0.]);
+    }
+
+    #[test]
+    fn lanes_not_swapped_without_crossings() {
+        let mut routes = vec![route(&[(0., 0.), (10., 0.), (10., -10.)], &[Some(1.), Some(0.)]),
+                              route(&[(0., 0.), (10., 0.), (10., 10.)], &[Some(-1.), Some(0.)])];
+        minimize_crossings(&mut routes);
+        assert_eq!(offsets(&routes[0]), vec![1., 0.]);
+        assert_eq!(offsets(&routes[1]), vec![-1., 0.]);
+    }
+
+    #[test]
+    fn lanes_not_swapped_onto_straight_runs() {
+        // swapping the lanes on the shared segment would remove the crossing,
+        // but make the first route shift lanes where it runs straight on
+        let mut routes = vec![route(&[(0., 0.), (10., 0.), (20., 0.), (20., -10.)],
+                                    &[Some(-1.), Some(-1.), Some(0.)]),
+                              route(&[(10., 0.), (20., 0.), (20., 10.)], &[Some(1.), Some(0.)])];
+        assert_eq!(crossings(&routes).len(), 1);
+        minimize_crossings(&mut routes);
+        assert_eq!(crossings(&routes).len(), 1);
+        assert_eq!(offsets(&routes[0]), vec![-1., -1., 0.]);
+        assert_eq!(offsets(&routes[1]), vec![1., 0.]);
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+
+use ast;
+
+/// A token of the map language. Punctuation consisting of a single character
+/// is represented by `Sym`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Tok<'input> {
+    Include,
+    Import,
+    Redefine,
+    For,
+    RSep,
+    RBase,
+    Bounds,
+    Style,
+    AutoOffsets,
+    MinimizeCrossings,
+    MaxDepth,
+    Group,
+    Routes,
+    Stop,
+    As,
+    In,
+    If,
+    Then,
+    Else,
+    And,
+    Or,
+    Not,
+    DotDot,
+    Arrow,
+    Diamond,
+    LessEq,
+    GreaterEq,
+    EqEq,
+    Sym(char),
+    /// `'`, which the grammar can't match as a `Sym`.
+    Quote,
+    SIdent(&'input str),
+    PIdent(&'input str),
+    LIdent(&'input str),
+    RIdent(&'input str),
+    StIdent(&'input str),
+    StrIdent(&'input str),
+    Name(&'input str),
+    PointFunction(&'input str),
+    LineFunction(&'input str),
+    Number(&'input str),
+    /// A string literal, including its quotes.
+    String(&'input str),
+    /// Text which is not a token, which the parser reports and recovers from.
+    Invalid(&'input str),
+}
+
+/// An error splitting the source into tokens, at the given offset.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LexError {
+    UnterminatedComment(usize),
+}
+
+const COMMANDS: &[(&str, Tok<'static>)] = &[
+    ("include", Tok::Include),
+    ("import", Tok::Import),
+    ("redefine", Tok::Redefine),
+    ("for", Tok::For),
+    ("r_sep", Tok::RSep),
+    ("r_base", Tok::RBase),
+    ("bounds", Tok::Bounds),
+    ("style", Tok::Style),
+    ("auto_offsets", Tok::AutoOffsets),
+    ("minimize_crossings", Tok::MinimizeCrossings),
+    ("max_depth", Tok::MaxDepth),
+    ("group", Tok::Group),
+    ("routes", Tok::Routes),
+    ("stop", Tok::Stop),
+];
+
+const KEYWORDS: &[(&str, Tok<'static>)] = &[
+    ("as", Tok::As),
+    ("in", Tok::In),
+    ("if", Tok::If),
+    ("then", Tok::Then),
+    ("else", Tok::Else),
+    ("and", Tok::And),
+    ("or", Tok::Or),
+    ("not", Tok::Not),
+];
+
+const OPERATORS: &[(&str, Tok<'static>)] = &[
+    ("..", Tok::DotDot),
+    ("->", Tok::Arrow),
+    ("<>", Tok::Diamond),
+    ("<=", Tok::LessEq),
+    (">=", Tok::GreaterEq),
+    ("==", Tok::EqEq),
+    ("'", Tok::Quote),
+];
+
+const SYMBOLS: &str = "+-*/()[]{},;:&|<>=";
+
+/// Splits source text into tokens for the parser, skipping whitespace and
+/// comments. Offsets are relative to the start of the text.
+///
+/// Doc comments (`///`) are collected in `docs`, joined by line and keyed by
+/// the offset of the token following them.
+pub struct Lexer<'input> {
+    text: &'input str,
+    pos: usize,
+    doc: Vec<&'input str>,
+    pub docs: HashMap<usize, String>,
+}
+
+impl<'input> Lexer<'input> {
+    pub fn new(text: &'input str) -> Lexer<'input> {
+        Lexer {
+            text,
+            pos: 0,
+            doc: Vec::new(),
+            docs: HashMap::new(),
+        }
+    }
+
+    /// Skips whitespace and comments, returning the offset of any
+    /// unterminated block comment.
+    fn skip(&mut self) -> Result<(), LexError> {
+        loop {
+            let rest = &self.text[self.pos..];
+            let space = rest.find(|c: char| !c.is_whitespace()).unwrap_or(rest.len());
+            let trimmed = &rest[space..];
+            self.pos += space;
+            if trimmed.starts_with("//") {
+                let len = trimmed.find('\n').unwrap_or(trimmed.len());
+                let comment = &trimmed[..len];
+                if comment.starts_with("///") && !comment.starts_with("////") {
+                    self.doc.push(comment[3..].trim());
+                }
+                self.pos += len;
+            } else if let Some(rest) = trimmed.strip_prefix("/*") {
+                match rest.find("*/") {
+                    Some(i) => self.pos += i + 4,
+                    None => return Err(LexError::UnterminatedComment(self.pos)),
+                }
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns the token at the current position and its length.
+    fn token(&self) -> (Tok<'input>, usize) {
+        lazy_static! {
+            static ref IDENT: Regex = Regex::new(r"^[$@!~%#](\w+::)*(\w|\{\$\w+\})+").unwrap();
+            static ref NAME: Regex = Regex::new(r"^[A-Za-z_]\w*").unwrap();
+            static ref COMMAND: Regex = Regex::new(r"^\\\w+").unwrap();
+            static ref NUMBER: Regex = Regex::new(r"^[0-9]+(\.[0-9]+)?").unwrap();
+            static ref STRING: Regex = Regex::new(r#"^"([^"\\]|\\["\\])*""#).unwrap();
+        }
+        let rest = &self.text[self.pos..];
+        if let Some(m) = IDENT.find(rest) {
+            let id = m.as_str();
+            let tok = match id.chars().next().unwrap() {
+                '$' => Tok::SIdent(id),
+                '@' => Tok::PIdent(id),
+                '!' => Tok::LIdent(id),
+                '~' => Tok::RIdent(id),
+                '%' => Tok::StIdent(id),
+                _ => Tok::StrIdent(id),
+            };
+            return (tok, id.len());
+        }
+        if let Some(m) = NAME.find(rest) {
+            let name = m.as_str();
+            let tok = if let Some(&(_, tok)) = KEYWORDS.iter().find(|&&(k, _)| k == name) {
+                tok
+            } else {
+                // calls to functions returning points or lines are told apart
+                // so that the grammar knows the type of the call
+                match ast::result_type(name) {
+                    Some(ast::Type::Point) => Tok::PointFunction(name),
+                    Some(ast::Type::Line) => Tok::LineFunction(name),
+                    _ => Tok::Name(name),
+                }
+            };
+            return (tok, name.len());
+        }
+        if let Some(m) = COMMAND.find(rest) {
+            let tok = COMMANDS.iter()
+                .find(|&&(c, _)| c == &m.as_str()[1..])
+                .map_or(Tok::Invalid(m.as_str()), |&(_, tok)| tok);
+            return (tok, m.end());
+        }
+        if let Some(m) = NUMBER.find(rest) {
+            return (Tok::Number(m.as_str()), m.end());
+        }
+        if let Some(m) = STRING.find(rest) {
+            return (Tok::String(m.as_str()), m.end());
+        }
+        if let Some(&(op, tok)) = OPERATORS.iter().find(|&&(op, _)| rest.starts_with(op)) {
+            return (tok, op.len());
+        }
+        let c = rest.chars().next().unwrap();
+        if SYMBOLS.contains(c) {
+            (Tok::Sym(c), 1)
+        } else {
+            (Tok::Invalid(&rest[..c.len_utf8()]), c.len_utf8())
+        }
+    }
+}
+
+impl<'input> Iterator for Lexer<'input> {
+    type Item = Result<(usize, Tok<'input>, usize), LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(err) = self.skip() {
+            // stop after reporting the error
+            self.pos = self.text.len();
+            return Some(Err(err));
+        }
+        if self.pos == self.text.len() {
+            return None;
+        }
+        let start = self.pos;
+        if !self.doc.is_empty() {
+            self.docs.insert(start, self.doc.join("\n"));
+            self.doc.clear();
+        }
+        let (tok, len) = self.token();
+        self.pos += len;
+        Some(Ok((start, tok, self.pos)))
+    }
+}
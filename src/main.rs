@@ -1,14 +1,24 @@
 #[macro_use] extern crate lazy_static;
+extern crate lalrpop_util;
 extern crate regex;
 extern crate ordered_float;
 
+use std::collections::HashMap;
 use std::env;
+use std::fs;
 use std::io;
 
 use std::error::Error;
 use std::fs::File;
 use std::io::prelude::*;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
 
+use source::SourceMap;
+
+// generated by lalrpop
+#[allow(clippy::all, unused_parens)]
 pub mod grammar;
 pub mod ast;
 pub mod errors;
@@ -16,6 +26,9 @@ pub mod command;
 pub mod math;
 pub mod route;
 pub mod stop;
+pub mod layout;
+pub mod source;
+pub mod lexer;
 
 macro_rules! raise {
     ($e: expr) => (Err(::std::convert::From::from($e)));
@@ -25,48 +38,632 @@ macro_rules! raise_str {
     ($e: expr) => (raise!(errors::Error::from_str($e)));
 }
 
-macro_rules! raise_val {
-    ($e: expr) => (raise!(errors::Error::from_val($e)));
+fn main() {
+    // compile on a thread with a known stack size, which bounds how deeply
+    // macros can be expanded
+    let child = thread::Builder::new()
+        .stack_size(ast::STACK_SIZE)
+        .spawn(run);
+    match child {
+        // the panic has already been reported
+        Ok(child) => if child.join().is_err() {
+            process::exit(101);
+        },
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(1);
+        },
+    }
 }
 
-fn main() {
-    let args = env::args().collect::<Vec<_>>();
+fn run() {
+    let mut args = env::args().collect::<Vec<_>>();
+    // treat warnings as errors
+    let deny = args.iter().any(|a| a == "--deny-warnings");
+    args.retain(|a| a != "--deny-warnings");
+    // report redefinitions without \redefine as warnings rather than errors
+    let warn_redef = args.iter().any(|a| a == "--warn-redefinitions");
+    args.retain(|a| a != "--warn-redefinitions");
+    // only write the output once the map has compiled
+    let mut svg = Vec::new();
     let res = match args.len() {
         // no args
         1 => {
             let mut infile = io::stdin();
-            let mut outfile = io::stdout();
-            parse(&mut infile, &mut outfile)
+            parse("<stdin>", &mut infile, &mut svg, deny, warn_redef)
+                .and_then(|_| write_stdout(&svg))
         },
         2 => {
-            let mut infile = File::open(&args[1]).unwrap();
-            let mut outfile = io::stdout();
-            parse(&mut infile, &mut outfile)
+            open(&args[1]).and_then(|mut infile| {
+                parse(&args[1], &mut infile, &mut svg, deny, warn_redef)?;
+                write_stdout(&svg)
+            })
         },
         3 => {
-            let mut infile = File::open(&args[1]).unwrap();
-            let mut outfile = File::create(&args[2]).unwrap();
-            parse(&mut infile, &mut outfile)
+            open(&args[1]).and_then(|mut infile| {
+                parse(&args[1], &mut infile, &mut svg, deny, warn_redef)?;
+                File::create(&args[2])
+                    .and_then(|mut outfile| outfile.write_all(&svg))
+                    .map_err(|e| errors::Error::io(&args[2], e).into())
+            })
         },
         _ => raise_str!("Too many arguments"),
     };
     if let Err(err) = res {
-        println!("{}", err);
+        eprintln!("{}", err);
+        process::exit(1);
     }
 }
 
-fn parse<R: Read, W: Write>(r: &mut R, w: &mut W) -> Result<(), Box<Error>> {
+fn write_stdout(svg: &[u8]) -> Result<(), Box<Error>> {
+    io::stdout().write_all(svg).map_err(|e| errors::Error::io("<stdout>", e))?;
+    Ok(())
+}
+
+fn open(path: &str) -> Result<File, Box<Error>> {
+    Ok(File::open(path).map_err(|e| errors::Error::io(path, e))?)
+}
+
+fn parse<R: Read, W: Write>(name: &str,
+                            r: &mut R,
+                            w: &mut W,
+                            deny: bool,
+                            warn_redef: bool) -> Result<(), Box<Error>> {
     let mut s = String::new();
-    r.read_to_string(&mut s)?;
-    match grammar::parse_Program(s.as_str()) {
-        Err(err) => raise_val!(err),
-        Ok(prog) => {
-            let mut vars = ast::Variables::new();
-            for st in prog {
-                st.eval(&mut vars)?;
+    r.read_to_string(&mut s).map_err(|e| errors::Error::io(name, e))?;
+    let mut sources = SourceMap::new();
+    sources.add(name, fs::canonicalize(name).ok(), s, None);
+    let (mut errs, warnings) = eval(&mut sources, w, deny, warn_redef);
+    for warning in warnings {
+        eprintln!("{}\n", warning.render("warning", &sources));
+    }
+    if errs.is_empty() {
+        return Ok(());
+    }
+    errs.sort_by_key(|err| {
+        err.downcast_ref::<errors::Error>()
+            .and_then(|e| e.span())
+            .map_or(usize::MAX, |span| span.start)
+    });
+    let mut report = String::new();
+    for err in errs.iter() {
+        match err.downcast_ref::<errors::Error>() {
+            Some(err) => report.push_str(err.render("error", &sources).as_ref()),
+            None => report.push_str(format!("error: {}", err).as_ref()),
+        }
+        report.push_str("\n\n");
+    }
+    report.push_str(format!("{} error{} found",
+                            errs.len(),
+                            if errs.len() == 1 { "" } else { "s" }).as_ref());
+    raise_str!(report.as_ref())
+}
+
+/// Compiles the map in the first file of `sources`, returning every error
+/// and warning found. Files included by the map are added to `sources`.
+///
+/// Definitions are evaluated before the statements using them, whatever
+/// their order in the source. Syntax errors are recovered from at the end of
+/// the statement, and statements which fail to evaluate are skipped. Errors
+/// from later statements which only refer to an identifier whose definition
+/// failed are not reported. If `deny` is set, warnings are reported as
+/// errors, and the map is not drawn. If `warn_redef` is set, redefinitions
+/// without `\redefine` are warnings rather than errors.
+fn eval<W: Write>(sources: &mut SourceMap,
+                  w: &mut W,
+                  deny: bool,
+                  warn_redef: bool) -> (Vec<Box<Error>>, Vec<errors::Error>) {
+    let mut errs = Vec::new();
+    let mut prog = Vec::new();
+    if !load(sources, 0, &mut Vec::new(), &mut Vec::new(), &mut prog, &mut errs) {
+        return (errs, Vec::new());
+    }
+    let (prog, cycles) = ast::order(prog);
+    let mut vars = ast::Variables::new();
+    vars.warn_redefinitions = warn_redef;
+    vars.declare(&prog);
+    let mut failed = Vec::new();
+    for err in cycles {
+        if let errors::ErrorKind::DefinitionCycle { ref chain } = *err.kind() {
+            failed.extend(chain.iter().cloned());
+        }
+        errs.push(Box::new(err) as Box<Error>);
+    }
+    for st in prog {
+        let defines = st.defines();
+        let mut found = Vec::new();
+        st.eval(&mut vars, &mut found);
+        if found.is_empty() {
+            continue;
+        }
+        for err in found {
+            let cascade = match err.downcast_ref::<errors::Error>().map(|e| e.kind()) {
+                Some(errors::ErrorKind::Undefined { ident, .. }) => failed.contains(ident),
+                _ => false,
+            };
+            if !cascade {
+                errs.push(err);
             }
-            vars.format(w)?;
-            Ok(())
         }
+        failed.extend(defines);
+    }
+    if !errs.is_empty() {
+        return (errs, Vec::new());
+    }
+    vars.layout();
+    let mut warnings = vars.crossings();
+    warnings.extend(vars.lint());
+    if deny && !warnings.is_empty() {
+        errs.extend(warnings.into_iter().map(|w| Box::new(w) as Box<Error>));
+        return (errs, Vec::new());
+    }
+    if let Err(err) = vars.format(w) {
+        errs.push(err);
+    }
+    (errs, warnings)
+}
+
+/// Parses the `i`th file of `sources`, appending its statements to `prog`.
+/// Each `\include` is replaced by the statements of the included file, unless
+/// that file is already in `loaded`, and each `\import` is loaded separately
+/// into a module. `stack` holds the files currently being loaded, to detect
+/// cycles.
+///
+/// Returns false if a syntax error could not be recovered from.
+fn load(sources: &mut SourceMap,
+        i: usize,
+        stack: &mut Vec<usize>,
+        loaded: &mut Vec<PathBuf>,
+        prog: &mut Vec<ast::Statement>,
+        errs: &mut Vec<Box<Error>>) -> bool {
+    let (parsed, mut docs) = {
+        let file = sources.get(i);
+        let mut recovered = Vec::new();
+        let mut lexer = lexer::Lexer::new(&file.text);
+        let parsed = grammar::parse_Program(&mut recovered, file.base, &mut lexer);
+        errs.extend(recovered.into_iter()
+            .map(|r| Box::new(errors::Error::parse(r.error, file.base, &file.text))
+                 as Box<Error>));
+        (parsed.map_err(|err| errors::Error::parse(err, file.base, &file.text)), lexer.docs)
+    };
+    let statements = match parsed {
+        Ok(statements) => statements,
+        Err(err) => {
+            errs.push(Box::new(err));
+            return false;
+        },
+    };
+    loaded.extend(sources.get(i).path.clone());
+    stack.push(i);
+    let base = sources.get(i).base;
+    let mut ok = true;
+    for st in statements {
+        let (path, module, span) = match st {
+            ast::Statement::Include(path, span) => (path, None, span),
+            ast::Statement::Import(path, name, span) => (path, Some(name), span),
+            st => {
+                prog.push(attach_doc(st, &mut docs, base));
+                continue;
+            },
+        };
+        let (name, path) = sources.resolve(i, path.as_ref());
+        let mut text = String::new();
+        let read = File::open(&path).and_then(|mut f| f.read_to_string(&mut text));
+        if let Err(e) = read {
+            errs.push(Box::new(errors::Error::io(name.as_ref(), e).at(span)));
+            continue;
+        }
+        let path = fs::canonicalize(&path).ok();
+        if let Some(k) = stack.iter().position(|&k| path.is_some() && sources.get(k).path == path) {
+            let mut chain = stack[k..].iter()
+                .map(|&k| sources.get(k).name.clone())
+                .collect::<Vec<_>>();
+            chain.push(name);
+            errs.push(Box::new(errors::Error::include_cycle(chain).at(span)));
+            continue;
+        }
+        match module {
+            None => {
+                if path.iter().any(|p| loaded.contains(p)) {
+                    continue;
+                }
+                let j = sources.add(name.as_ref(), path, text, Some(span));
+                ok = load(sources, j, stack, loaded, prog, errs) && ok;
+            },
+            Some(module) => {
+                let j = sources.add(name.as_ref(), path, text, Some(span));
+                let mut body = Vec::new();
+                ok = load(sources, j, stack, &mut Vec::new(), &mut body, errs) && ok;
+                prog.push(ast::Statement::Module(module, body, span));
+            },
+        }
+    }
+    stack.pop();
+    ok
+}
+
+/// Wraps `st`, and the statements in its body, in the doc comments preceding
+/// them.
+fn attach_doc(st: ast::Statement,
+              docs: &mut HashMap<usize, String>,
+              base: usize) -> ast::Statement {
+    let doc = st.span().and_then(|span| docs.remove(&(span.start - base)));
+    let st = match st {
+        ast::Statement::For(id, range, body, span) => {
+            let body = body.into_iter().map(|st| attach_doc(st, docs, base)).collect();
+            ast::Statement::For(id, range, body, span)
+        },
+        st => st,
+    };
+    match doc {
+        Some(doc) => ast::Statement::Doc(doc, Box::new(st)),
+        None => st,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::prelude::*;
+    use std::path::PathBuf;
+
+    use std::thread;
+
+    use ast;
+    use errors;
+    use source::SourceMap;
+    use super::eval;
+
+    /// A map which can be drawn, for tests which need output.
+    const SETUP: &str = "\\bounds 0, 0, 100, 100;\n\\style \"a.css\";\n";
+
+    /// Compiles the sources, returning the rendered errors, the rendered
+    /// warnings and the output.
+    fn run(sources: &mut SourceMap, warn_redef: bool) -> (Vec<String>, Vec<String>, String) {
+        let mut out = Vec::new();
+        let (errs, warnings) = eval(sources, &mut out, false, warn_redef);
+        let errs = errs.iter()
+            .map(|err| match err.downcast_ref::<errors::Error>() {
+                Some(err) => err.render("error", sources),
+                None => err.to_string(),
+            })
+            .collect();
+        let warnings = warnings.iter().map(|w| w.render("warning", sources)).collect();
+        (errs, warnings, String::from_utf8(out).unwrap())
+    }
+
+    fn compile(text: &str) -> (Vec<String>, Vec<String>, String) {
+        let mut sources = SourceMap::new();
+        sources.add("test.subway", None, String::from(text), None);
+        run(&mut sources, false)
+    }
+
+    /// Compiles `text`, asserting that it has no errors.
+    fn ok(text: &str) -> String {
+        let (errs, _, out) = compile(text);
+        assert!(errs.is_empty(), "unexpected errors: {:?}", errs);
+        out
+    }
+
+    /// Compiles `text`, asserting that it has exactly one error, which
+    /// contains `message`.
+    fn fails(text: &str, message: &str) -> String {
+        let (mut errs, _, _) = compile(text);
+        assert_eq!(errs.len(), 1, "expected one error, got {:?}", errs);
+        let err = errs.pop().unwrap();
+        assert!(err.contains(message), "expected {:?} in {:?}", message, err);
+        err
+    }
+
+    /// Asserts that `$r` evaluates to `value`, by using it as the width of
+    /// the map.
+    fn evaluates(text: &str, value: i32) {
+        let out = ok(&format!("{}\n\\bounds 0, 0, $r, 1;", text));
+        assert!(out.contains(&format!("<svg width=\"{}\"", value)), "{}", out);
+    }
+
+    /// Writes `files` to a fresh directory, returning its path.
+    fn write_files(dir: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = env::temp_dir().join(format!("subway_map_{}_{}", dir, ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for &(name, text) in files {
+            File::create(dir.join(name)).unwrap().write_all(text.as_bytes()).unwrap();
+        }
+        dir
+    }
+
+    fn compile_file(dir: &PathBuf, name: &str) -> (Vec<String>, String) {
+        let path = dir.join(name);
+        let mut text = String::new();
+        File::open(&path).unwrap().read_to_string(&mut text).unwrap();
+        let mut sources = SourceMap::new();
+        sources.add(name, fs::canonicalize(&path).ok(), text, None);
+        let (errs, _, out) = run(&mut sources, false);
+        fs::remove_dir_all(dir).unwrap();
+        (errs, out)
+    }
+
+    #[test]
+    fn error_spans() {
+        let err = fails("@a = (0, 0);\n@b = @a + @c;", "Variable @c not defined");
+        assert!(err.contains("test.subway:2:11"), "{}", err);
+        // the caret covers the whole of a character several bytes long
+        let err = fails("$a = 1 \u{2192} 2;", "\u{2192}");
+        assert!(err.contains("test.subway:1:8"), "{}", err);
+        assert!(err.contains("|        ^") && !err.contains("^^"), "{}", err);
+    }
+
+    #[test]
+    fn several_errors() {
+        let (errs, _, _) = compile("$a = 1 ? 2;\n$b = \\bogus;\n$c = ;\n$d = 1 \u{2192} 2;\n$e = 1;");
+        assert_eq!(errs.len(), 4, "{:?}", errs);
+        assert!(errs[0].contains("Invalid token `?`"), "{:?}", errs);
+        assert!(errs[1].contains("Invalid token `\\bogus`"), "{:?}", errs);
+        assert!(errs[2].contains("Unexpected token `;`"), "{:?}", errs);
+        assert!(errs[3].contains("Invalid token `\u{2192}`"), "{:?}", errs);
+        let (errs, _, _) = compile("@a = @x;\n@b = @y;");
+        assert_eq!(errs.len(), 2, "{:?}", errs);
+    }
+
+    #[test]
+    fn suggestions() {
+        fails("@bdwy_59 = (0, 0);\n@q = @bdwy_95;", "did you mean @bdwy_59?");
+        fails("!8av = (0, 0) <> (0, 1);\n@q = @8av;", "note that !8av is a line");
+        // names defined later in the file are suggested too
+        fails("@q = @pp;\n@pq = (0, 0);", "did you mean @pq?");
+    }
+
+    #[test]
+    fn warnings() {
+        let text = format!("{}@a = (0, 0);\n@b = @a;\n$f[$x] = $x;\n~r = @a ', (10, 0);\n\
+                            \\routes{{~r}} \"one\";\n\\routes{{~r}} \"two\";", SETUP);
+        let (errs, warnings, _) = compile(&text);
+        assert!(errs.is_empty(), "{:?}", errs);
+        assert_eq!(warnings.len(), 3, "{:?}", warnings);
+        assert!(warnings[0].contains("Unused point @b"), "{:?}", warnings);
+        assert!(warnings[1].contains("Unused scalar macro $f"), "{:?}", warnings);
+        assert!(warnings[2].contains("Route ~r is drawn by more than one \\routes command"),
+                "{:?}", warnings);
+
+        let mut sources = SourceMap::new();
+        sources.add("test.subway", None, text, None);
+        let mut out = Vec::new();
+        let (errs, warnings) = eval(&mut sources, &mut out, true, false);
+        assert_eq!(errs.len(), 3, "{:?}", errs);
+        assert!(warnings.is_empty());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn degenerate_geometry() {
+        fails("@p = ((0, 0) <> (1, 0)) & ((0, 1) <> (1, 1));", "are parallel, and do not intersect");
+        fails("@p = ((0, 0) <> (0, 0)) & ((0, 1) <> (1, 1));", "has no direction");
+    }
+
+    #[test]
+    fn conditionals() {
+        evaluates("$x = 3;\n$r = if $x > 2 and not ($x == 4) then 1 else 2;", 1);
+        evaluates("$r = if 0 or 0 then 1 else 2;", 2);
+        evaluates("$r = (1 < 2) + (2 <= 2) + (3 >= 4) + (1 or 0) + (1 and 0);", 3);
+        evaluates("$r = if 1 then if 0 then 1 else 2 else 3;", 2);
+        ok("$x = 1;\n@p = if $x then (1, 0) else (0, 0);\n\
+            !l = if $x > 1 then @p <> (0, 1) else @p <> (1, 1);\n@q = !l & ((0, 0) <> (1, 0));");
+    }
+
+    #[test]
+    fn loops() {
+        evaluates("\\for $i in 0..3 { $s_{$i} = $i * 10; }\n$r = $s_2;", 20);
+        evaluates("\\for $i in [2, 5] { $s_{$i} = $i; }\n$r = $s_2 + $s_5;", 7);
+        fails("\\for $i in [0.5] { $s_{$i} = 1; }", "0.5 is not an integer");
+        fails("\\for $i in 0..(1 / 0) { $s_{$i} = 1; }", "does not have finite bounds");
+        fails("\\for $i in 0..1000000 { $s_{$i} = 1; }", "more than the maximum");
+    }
+
+    #[test]
+    fn include_cycles() {
+        let dir = write_files("include", &[
+            ("a.subway", "\\include \"b.subway\";\n@a = (0, 0);"),
+            ("b.subway", "\\include \"a.subway\";\n@b = (0, 0);"),
+        ]);
+        let (errs, _) = compile_file(&dir, "a.subway");
+        assert_eq!(errs.len(), 1, "{:?}", errs);
+        assert!(errs[0].contains("a.subway includes b.subway includes a.subway"), "{:?}", errs);
+    }
+
+    #[test]
+    fn includes_once() {
+        let dir = write_files("once", &[
+            ("a.subway", "\\include \"b.subway\";\n\\include \"c.subway\";"),
+            ("b.subway", "\\include \"c.subway\";"),
+            ("c.subway", "@c = (0, 0);"),
+        ]);
+        let (errs, _) = compile_file(&dir, "a.subway");
+        assert!(errs.is_empty(), "{:?}", errs);
+    }
+
+    #[test]
+    fn imports() {
+        let main = format!("{}\\import \"streets.subway\" as streets;\n\
+                            @a = @streets::times_sq;\n\\routes{{~streets::shuttle}} \"s\";", SETUP);
+        let dir = write_files("modules", &[
+            ("a.subway", &main),
+            ("streets.subway", "@times_sq = (0, 0);\n~shuttle = @times_sq ', (10, 0);"),
+        ]);
+        let (errs, out) = compile_file(&dir, "a.subway");
+        assert!(errs.is_empty(), "{:?}", errs);
+        assert!(out.contains("id=\"streets__shuttle\""), "{}", out);
+        assert!(out.contains("class=\"route r_streets__shuttle\""), "{}", out);
+
+        let dir = write_files("import", &[
+            ("a.subway", "\\import \"b.subway\" as b;\n@a = @b::b;"),
+            ("b.subway", "\\import \"a.subway\" as a;\n@b = (0, 0);"),
+        ]);
+        let (errs, _) = compile_file(&dir, "a.subway");
+        assert_eq!(errs.len(), 1, "{:?}", errs);
+        assert!(errs[0].contains("a.subway includes b.subway includes a.subway"), "{:?}", errs);
+    }
+
+    #[test]
+    fn route_macros() {
+        let out = ok(&format!("{}~t[@p, @q] = @p ', @q;\n~u = ~t[(0, 0), (20, 0)];\n\
+                               \\routes{{~u}} \"r\";", SETUP));
+        assert!(out.contains("M 0, 0\nL 20, 0\n"), "{}", out);
+        fails("~t[@p, @q] = @p ', @q;\n~u = ~t[(0, 0)];", "Missing argument @q to macro ~t");
+    }
+
+    #[test]
+    fn stop_macros() {
+        let out = ok(&format!("{}~r = (0, 0) ', (10, 0);\n\\routes{{~r}} \"r\";\n\
+                               %st[$x] = (0, 0) ', (10, 0) & ($x, 0) <> ($x, 10) {{+}};\n\
+                               \\stop %st[5] \"Fifth\" \"fifth\";", SETUP));
+        assert!(out.contains("<text x=\"5\" y=\"0\" id=\"sfifth\""), "{}", out);
+        assert!(out.contains(">Fifth</text>"), "{}", out);
+        fails("%st[$x] = (0, 0) ', (10, 0) & ($x, 0) <> ($x, 10) {+};\n\
+               \\stop %st[(0, 0)] \"a\" \"a\";", "Argument $x to macro %st is not a Scalar");
+    }
+
+    #[test]
+    fn functions() {
+        evaluates("$r = sqrt(16) + abs(-2) + max(1, 3) + min(1, 3) + floor(2.5) + round(2.5);", 15);
+        evaluates("$r = distance((0, 0), (3, 4));", 5);
+        fails("$x = sqr(2);", "Unknown function sqr; did you mean sqrt?");
+        fails("$x = sqrt(1, 2);", "Incorrect number of arguments to function sqrt");
+        fails("@x = sqrt(2);", "Function sqrt returns a scalar, not a point");
+    }
+
+    #[test]
+    fn geometric_functions() {
+        evaluates("@m = midpoint((0, 0), (8, 2));\n$r = distance(@m, (4, 0));", 1);
+        evaluates("@p = project((3, 4), (0, 0) <> (1, 0));\n$r = distance(@p, (3, 0));", 0);
+        fails("$x = midpoint((0, 0), (2, 0));", "Function midpoint returns a point, not a scalar");
+        fails("!l = midpoint((0, 0), (2, 0));", "Function midpoint returns a point, not a line");
+        fails("@x = midpt((0, 0), (2, 0));", "Unknown function midpt");
+    }
+
+    #[test]
+    fn angles() {
+        evaluates("$r = round(angle((0, 0) < N));", 270);
+        evaluates("$r = round(angle((0, 0) < 45));", 45);
+        evaluates("$r = round(angle((1, 1) <> (0, 0)));", 225);
+        evaluates("$r = round(atan2(-1, 0));", 270);
+        evaluates("$r = round(sin(90) + cos(180) + tan(45));", 1);
+        evaluates("@p = rotate((1, 0), (0, 0), S);\n$r = round(distance(@p, (0, 1)));", 0);
+        fails("!l = (0, 0) < (1 / 0);", "non-finite coordinates");
+    }
+
+    #[test]
+    fn strings() {
+        let out = ok(&format!("{}~r = (0, 0) ', (10, 0);\n\\routes{{~r}} \"r\";\n\
+                               #name[$n] = \"{{$n}} St\";\n#id = \"st_\";\n\
+                               \\for $n in [50] {{\n\
+                                   \\stop (0, 0) ', (10, 0) & (5, 0) <> (5, 10) {{+}} #name[$n] #id + \"{{$n}}\";\n\
+                               }}", SETUP));
+        assert!(out.contains("id=\"sst_50\""), "{}", out);
+        assert!(out.contains(">50 St</text>"), "{}", out);
+        fails("#s = \"{$x} St\";", "Variable $x not defined");
+    }
+
+    #[test]
+    fn comments() {
+        evaluates("// $r = 1;\n$r = /* 2 + */ 3; // 4\n/* $r = 5;\n */", 3);
+        evaluates("$r = 1 /* a \"quoted */ + 2;", 3);
+        let err = fails("$r = 1;\n/* $r = 2;", "Unterminated block comment");
+        assert!(err.contains("test.subway:2:1"), "{}", err);
+    }
+
+    #[test]
+    fn doc_comments() {
+        let out = ok(&format!("{}@a = (0, 0);\n@b = (10, 0);\n\
+                               /// Costs {{$price}} & more\n\
+                               //// not a doc comment\n\
+                               /// second line\n\
+                               ~r = @a ', @b;\n\\routes{{~r}} \"r\";", SETUP));
+        assert!(out.contains("Costs {$price} &amp; more"), "{}", out);
+        assert!(out.contains("second line"), "{}", out);
+        assert!(!out.contains("not a doc comment"), "{}", out);
+    }
+
+    #[test]
+    fn definitions_in_any_order() {
+        evaluates("$r = $a + $b;\n$a = 2 * $b;\n$b = 1;", 3);
+        evaluates("$r = $f[2];\n$f[$x] = $g[$x] + 1;\n$g[$x] = $x * 10;", 21);
+        ok("@a = @b + (1, 0);\n\\for $i in 0..3 { @p_{$i} = @b; }\n@b = (0, 0);\n@q = @p_2;");
+    }
+
+    #[test]
+    fn definition_cycles() {
+        let (errs, _, _) = compile("$a = $b;\n$b = $a;");
+        assert_eq!(errs.len(), 1, "{:?}", errs);
+        assert!(errs[0].contains("$a") && errs[0].contains("$b"), "{:?}", errs);
+    }
+
+    #[test]
+    fn redefinition() {
+        let err = fails("$r = 1;\n$r = 2;", "Redefinition of scalar $r without \\redefine");
+        assert!(err.contains("previously defined at test.subway:1:1"), "{}", err);
+        evaluates("$a = 1;\n$b = $a;\n\\redefine $a = 5;\n$r = $a + $b;", 6);
+
+        let mut sources = SourceMap::new();
+        sources.add("test.subway", None, String::from("$r = 1;\n$r = 2;"), None);
+        let (errs, warnings, _) = run(&mut sources, true);
+        assert!(errs.is_empty(), "{:?}", errs);
+        assert!(warnings.iter().any(|w| w.contains("Redefinition of scalar $r")),
+                "{:?}", warnings);
+    }
+
+    #[test]
+    fn named_and_default_args() {
+        let f = "$f[$a, $b = 2] = $a * 10 + $b;";
+        evaluates(&format!("{}\n$r = $f[1];", f), 12);
+        evaluates(&format!("{}\n$r = $f[1, 3];", f), 13);
+        evaluates(&format!("{}\n$r = $f[$b = 4, $a = 1];", f), 14);
+        evaluates("$g[$a, $b = $a + 1] = $a * 10 + $b;\n$r = $g[3];", 34);
+        fails(&format!("{}\n$r = $f[$c = 1];", f), "Macro $f has no argument $c");
+        fails(&format!("{}\n$r = $f[1, $a = 1];", f), "Argument $a to macro $f is given more than once");
+        fails(&format!("{}\n$r = $f[$b = 1];", f), "Missing argument $a to macro $f");
+        fails(&format!("{}\n$r = $f[1, 2, 3];", f), "Incorrect number of arguments to macro $f");
+    }
+
+    #[test]
+    fn macro_arguments() {
+        let apply = "$apply[$f[$x], $y] = $f[$y];";
+        evaluates(&format!("{}\n$double[$x] = 2 * $x;\n$r = $apply[$double, 4];", apply), 8);
+        fails(&format!("{}\n$g[$a, $b] = $a;\n$r = $apply[$g, 1];", apply),
+              "Argument $f to macro $apply must be a macro like $f[$x]");
+        fails(&format!("{}\n$r = $apply[1, 1];", apply), "Argument $f to macro $apply");
+        // a macro argument is expanded where it was passed from
+        fails("$apply[$f[$x], $y] = $f[$y];\n$g[$x] = $y;\n$r = $apply[$g, 3];",
+              "$y");
+    }
+
+    #[test]
+    fn depth_limit() {
+        let f = "$f[$n] = $f[$n + 1];\n$r = $f[0];";
+        let err = fails(&format!("\\max_depth 20;\n{}", f),
+                        "Expansion of macro $f exceeds the maximum depth of 20");
+        assert!(err.contains("in expansion of $f at test.subway:3:6"), "{}", err);
+        assert!(err.contains("... 10 more expansions"), "{}", err);
+        assert_eq!(err.matches("in expansion of").count(), 10, "{}", err);
+        evaluates("\\max_depth 30;\n$f[$n] = if $n <= 0 then 0 else 1 + $f[$n - 1];\n$r = $f[25];", 25);
+        fails("\\max_depth 0;", "Maximum depth must be a whole number");
+        fails("\\max_depth 2.5;", "Maximum depth must be a whole number");
+        fails("\\max_depth 100000;", "Maximum depth must be a whole number");
+    }
+
+    #[test]
+    fn stack_limit() {
+        let sum = vec!["$n"; 40].join(" + ");
+        let text = format!("\\max_depth 10000;\n\
+                            $f[$n] = if $n <= 0 then 0 else {} + $f[$n - 1];\n\
+                            $r = $f[9999];", sum);
+        // the map is compiled on a thread with a stack of the same size
+        let child = thread::Builder::new()
+            .stack_size(ast::STACK_SIZE)
+            .spawn(move || compile(&text).0)
+            .unwrap();
+        let errs = child.join().unwrap();
+        assert_eq!(errs.len(), 1, "{:?}", errs);
+        assert!(errs[0].contains("would overflow the stack"), "{:?}", errs);
     }
 }
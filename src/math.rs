@@ -14,6 +14,16 @@ pub enum Expr {
     Line(Line),
 }
 
+impl Expr {
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            Expr::Scalar(_) => "scalar",
+            Expr::Point(_) => "point",
+            Expr::Line(_) => "line",
+        }
+    }
+}
+
 pub type Scalar = f64;
 
 #[derive(Clone, Copy, Debug)]
@@ -24,16 +34,42 @@ impl Point {
         self.0.hypot(self.1)
     }
 
-    pub fn unit(self) -> Point {
-        self / self.abs()
+    /// Returns the unit vector in the direction of `self`, or `None` if it has
+    /// zero length.
+    pub fn unit(self) -> Option<Point> {
+        let abs = self.abs();
+        if abs > 0.0 && abs.is_finite() {
+            Some(self / abs)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_finite(self) -> bool {
+        self.0.is_finite() && self.1.is_finite()
+    }
+
+    /// Returns the point at distance `r` from the origin, at angle `theta`.
+    pub fn polar(r: Scalar, theta: Scalar) -> Point {
+        Point(r * theta.cos(), r * theta.sin())
+    }
+
+    /// Rotates `self` by `theta` about `center`. Positive angles turn from the
+    /// x axis towards the y axis.
+    pub fn rotate(self, center: Point, theta: Scalar) -> Point {
+        let (s, c) = theta.sin_cos();
+        let Point(x, y) = self - center;
+        center + Point(x * c - y * s, x * s + y * c)
     }
 
     pub fn perp(self) -> Point {
         Point(self.1, -self.0)
     }
 
+    /// Offsets `self` by `u` along `vector` and `v` perpendicular to it.
+    /// `vector` must have non-zero length.
     pub fn basis(self, vector: Point, u: Scalar, v: Scalar) -> Point {
-        let Point(c, s) = vector.unit();
+        let Point(c, s) = vector / vector.abs();
         self + Point(u * c + v * s, u * s - v * c)
     }
 
@@ -128,16 +164,42 @@ impl Add<Point> for Line {
     }
 }
 
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "({}) -> ({})", self.origin, self.vector)
+    }
+}
+
 impl Line {
-    pub fn intersect(self, other: Line) -> Point {
+    /// Returns the point where two lines meet, or `None` if they are parallel.
+    pub fn intersect(self, other: Line) -> Option<Point> {
         let Point(x2, y2) = self.origin - self.vector;
         let Point(x4, y4) = other.origin - other.vector;
         let Point(dx1, dy1) = self.vector;
         let Point(dx2, dy2) = other.vector;
         let det = dx1*dy2 - dx2*dy1;
+        if det.abs() <= 1e-12 * self.vector.abs() * other.vector.abs() {
+            return None;
+        }
         let det1 = self.origin.0*y2 - self.origin.1*x2;
         let det2 = other.origin.0*y4 - other.origin.1*x4;
-        Point(det1*dx2 - dx1*det2, det1*dy2 - dy1*det2) / det
+        let p = Point(det1*dx2 - dx1*det2, det1*dy2 - dy1*det2) / det;
+        if p.is_finite() { Some(p) } else { None }
+    }
+
+    /// Returns the point on the line closest to `p`.
+    pub fn project(self, p: Point) -> Point {
+        let Point(dx, dy) = self.vector;
+        let Point(x, y) = p - self.origin;
+        self.origin + self.vector * ((x * dx + y * dy) / (dx * dx + dy * dy))
+    }
+
+    /// Returns the line through the intersection of two lines, halfway between
+    /// their directions, or `None` if they are parallel.
+    pub fn bisector(self, other: Line) -> Option<Line> {
+        let origin = self.intersect(other)?;
+        let vector = self.vector.unit()? + other.vector.unit()?;
+        Some(Line { origin, vector })
     }
 
     pub fn offset(self, offset: Scalar) -> Line {
@@ -1,15 +1,19 @@
 use std::error::Error;
-use std::f64::consts::FRAC_PI_2;
+use std::f64::consts::{FRAC_PI_2, PI};
 
 use std::io::prelude::*;
 
+use errors;
+use errors::finite;
 use ast::Variables;
+use command::format_doc;
 use math::{Point, Scalar, Line};
 
 #[derive(Clone, Debug)]
 pub struct Route {
     segments: Vec<Segment>,
-    offsets: Vec<Scalar>,
+    offsets: Vec<Option<Scalar>>,
+    doc: Option<String>,
 }
 
 impl Route {
@@ -17,25 +21,54 @@ impl Route {
         Route {
             segments: Vec::new(),
             offsets: Vec::new(),
+            doc: None,
         }
     }
 
-    pub fn push(&mut self, s: Segment, o: Scalar) {
+    pub fn push(&mut self, s: Segment, o: Option<Scalar>) {
         self.segments.push(s);
         self.offsets.push(o);
     }
 
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// Returns the offset of the `i`th segment, or `None` if it has not been
+    /// assigned yet.
+    pub fn offset(&self, i: usize) -> Option<Scalar> {
+        self.offsets[i]
+    }
+
+    pub fn set_offset(&mut self, i: usize, o: Scalar) {
+        self.offsets[i] = Some(o);
+    }
+
+    pub fn set_doc(&mut self, doc: Option<String>) {
+        self.doc = doc;
+    }
+
     pub fn format_def<W: Write>(&self,
                                 w: &mut W,
                                 vars: &Variables,
                                 id: &String) -> Result<(), Box<Error>> {
+        let offsets = self.offsets.iter()
+            .map(|o| o.unwrap_or(0.0))
+            .collect::<Vec<_>>();
         writeln!(w, r#"<path id="{}" d=""#, id)?;
-        self.segments[0].format_start(w, vars, self.offsets[0])?;
+        self.segments[0].format_start(w, vars, offsets[0])?;
         for (i, seg) in self.segments[1..].iter().enumerate() {
-            self.segments[i].arc_to(w, vars, *seg, self.offsets[i], self.offsets[i+1])?;
+            self.segments[i].arc_to(w, vars, *seg, offsets[i], offsets[i+1])?;
+        }
+        self.segments.last().unwrap().format_end(w, vars, *offsets.last().unwrap())?;
+        match self.doc {
+            Some(ref doc) => {
+                writeln!(w, r#"">"#)?;
+                format_doc(w, doc)?;
+                writeln!(w, "</path>")?;
+            },
+            None => writeln!(w, r#"" />"#)?,
         }
-        self.segments.last().unwrap().format_end(w, vars, *self.offsets.last().unwrap())?;
-        writeln!(w, r#"" />"#)?;
         Ok(())
     }
 }
@@ -85,14 +118,18 @@ impl Segment {
                 return Ok(());
             }
             // otherwise, parallel shifts
-            let delta = (off_out - off_in) * vars.r_sep;
+            let delta = ((off_out - off_in) * vars.r_sep).abs();
             let p0 = self.end.basis(in_dir, -delta, inr);
             let p1 = self.end.basis(in_dir, 0.0, inr);
             let p2 = self.end.basis(in_dir, 0.0, outr);
             let p3 = self.end.basis(in_dir, delta, outr);
-            writeln!(w, "L {} C {} {} {}", p0, p1, p2, p3)?;
+            writeln!(w, "L {} C {} {} {}", finite(p0)?, finite(p1)?, finite(p2)?, finite(p3)?)?;
             return Ok(());
         }
+        if (in_dir.angle(out_dir) - PI).abs() < 1e-3 {
+            Err(errors::Error::geometry(
+                    format!("Route doubles back on itself at ({})", self.end).as_ref()))?;
+        }
         // rounded corner
         let sweep;
         let in_delta;
@@ -114,10 +151,10 @@ impl Segment {
         let start = p.basis(in_dir, -l, 0.0);
         let end = p.basis(out_dir, l, 0.0);
         writeln!(w, "L {start} A {r},{r} 0 0 {sweep} {end}",
-                 start=start,
+                 start=finite(start)?,
                  r=r,
                  sweep=sweep,
-                 end=end)?;
+                 end=finite(end)?)?;
         Ok(())
     }
 
@@ -126,7 +163,7 @@ impl Segment {
                             vars: &Variables,
                             off: Scalar) -> Result<(), Box<Error>> {
         let p = self.end.basis(self.dir(), 0.0, off * vars.r_sep);
-        writeln!(w, "L {}", p)?;
+        writeln!(w, "L {}", finite(p)?)?;
         Ok(())
     }
 
@@ -135,7 +172,7 @@ impl Segment {
                               vars: &Variables,
                               off: Scalar) -> Result<(), Box<Error>> {
         let p = self.start.basis(self.dir(), 0.0, off * vars.r_sep);
-        writeln!(w, "M {}", p)?;
+        writeln!(w, "M {}", finite(p)?)?;
         Ok(())
     }
 }
@@ -0,0 +1,70 @@
+use std::path::{Path, PathBuf};
+
+use errors::Span;
+
+/// A file making up part of a map.
+#[derive(Clone, Debug)]
+pub struct SourceFile {
+    /// The name used to refer to the file in messages.
+    pub name: String,
+    /// The canonical path of the file, if it was read from disk.
+    pub path: Option<PathBuf>,
+    pub text: String,
+    /// The offset of the start of the file.
+    pub base: usize,
+    /// The `\include` statement which loaded the file.
+    pub included_from: Option<Span>,
+}
+
+/// The files making up a map, laid out end to end so that a single offset
+/// identifies both a file and a position within it.
+#[derive(Clone, Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap {
+            files: Vec::new(),
+        }
+    }
+
+    /// Adds a file, returning its index.
+    pub fn add(&mut self,
+               name: &str,
+               path: Option<PathBuf>,
+               text: String,
+               included_from: Option<Span>) -> usize {
+        // leave a gap so that the end of one file is not the start of the next
+        let base = self.files.last().map_or(0, |f| f.base + f.text.len() + 1);
+        self.files.push(SourceFile {
+            name: String::from(name),
+            path,
+            text,
+            base,
+            included_from,
+        });
+        self.files.len() - 1
+    }
+
+    pub fn get(&self, i: usize) -> &SourceFile {
+        &self.files[i]
+    }
+
+    /// Finds the index of the file containing `offset`.
+    pub fn find(&self, offset: usize) -> usize {
+        self.files.iter().rposition(|f| f.base <= offset).unwrap_or(0)
+    }
+
+    /// Resolves a path written in the `i`th file relative to that file's
+    /// directory, returning a name for messages and the path to read.
+    pub fn resolve(&self, i: usize, path: &str) -> (String, PathBuf) {
+        let dir = self.files[i].path.as_ref()
+            .and_then(|p| p.parent())
+            .map_or(PathBuf::new(), |p| p.to_path_buf());
+        let name = Path::new(&self.files[i].name).parent()
+            .map_or(PathBuf::from(path), |p| p.join(path));
+        (name.to_string_lossy().into_owned(), dir.join(path))
+    }
+}
@@ -3,27 +3,44 @@ use std::error::Error;
 use std::io::prelude::*;
 
 use errors;
+use errors::finite;
 
 use ast::{LabelPos, Variables};
+use command::format_doc;
 use math::{Line, Point};
 use route::Segment;
 
 #[derive(Clone, Debug)]
 pub enum Stop {
-    Line(Segment, Line, LabelPos, String),
-    Segment(Segment, Segment, LabelPos, LabelPos, String),
+    Line(Segment, Line, LabelPos),
+    Segment(Segment, Segment, LabelPos, LabelPos),
 }
 
 impl Stop {
     pub fn format_def<W: Write>(&self,
                                 buf: &mut W,
                                 vars: &Variables,
-                                id: &String) -> Result<(), Box<Error>> {
+                                label: &String,
+                                id: &String,
+                                doc: Option<&String>) -> Result<(), Box<Error>> {
         match *self {
-            Stop::Line(seg, line, pos, ref label) => {
-                unimplemented!();
+            Stop::Line(seg, line, pos) => {
+                let min = vars.min_offset(seg).ok_or(errors::Error::segment(seg))?;
+                let max = vars.max_offset(seg).ok_or(errors::Error::segment(seg))?;
+                let off = match pos {
+                    LabelPos::Plus => max + 1.0,
+                    LabelPos::Minus => min - 1.0,
+                } * vars.r_sep;
+                let p0 = intersect(seg.line(), line)?;
+                let p = intersect(seg.line().offset(off), line)?;
+                let (dx, dy) = p.cmp(p0);
+                Stop::marker_at(buf,
+                                intersect(seg.line().offset(min * vars.r_sep), line)?,
+                                intersect(seg.line().offset(max * vars.r_sep), line)?,
+                                id)?;
+                Stop::label_at(buf, p, dx, dy, label, id, doc)?;
             },
-            Stop::Segment(a, b, pos_a, pos_b, ref label) => {
+            Stop::Segment(a, b, pos_a, pos_b) => {
                 let off_a = match pos_a {
                     LabelPos::Plus => {
                         vars.max_offset(a)
@@ -44,29 +61,39 @@ impl Stop {
                             .ok_or(errors::Error::segment(b))? - 1.0
                     }
                 } * vars.r_sep;
-                let p0 = a.line().intersect(b.line());
-                let p = a.line().offset(off_a).intersect(b.line().offset(off_b));
+                let p0 = intersect(a.line(), b.line())?;
+                let p = intersect(a.line().offset(off_a), b.line().offset(off_b))?;
                 let (dx, dy) = p.cmp(p0);
-                Stop::label_at(buf, p, dx, dy, label, id)?;
+                Stop::label_at(buf, p, dx, dy, label, id, doc)?;
             },
         }
         Ok(())
     }
 
-    pub fn format_use<W: Write>(&self,
-                                buf: &mut W,
-                                vars: &Variables,
-                                id: &String) -> Result<(), Box<Error>> {
+    pub fn format_use<W: Write>(&self, buf: &mut W, id: &String) -> Result<(), Box<Error>> {
+        if let Stop::Line(..) = *self {
+            writeln!(buf, r##"<use xlink:href="#m{0}" class="marker m_{0}"/>"##, id)?;
+        }
         writeln!(buf, r##"<use xlink:href="#s{0}" class="stop s_{0}"/>"##, id)?;
         Ok(())
     }
 
+    fn marker_at<W: Write>(buf: &mut W,
+                           a: Point,
+                           b: Point,
+                           id: &String) -> Result<(), Box<Error>> {
+        writeln!(buf, r#"<path id="m{}" d="M {} L {}" />"#, id, finite(a)?, finite(b)?)?;
+        Ok(())
+    }
+
     fn label_at<W: Write>(buf: &mut W,
                           p: Point,
                           x: Ordering,
                           y: Ordering,
                           label: &String,
-                          id: &String) -> Result<(), Box<Error>> {
+                          id: &String,
+                          doc: Option<&String>) -> Result<(), Box<Error>> {
+        let p = finite(p)?;
         write!(buf, r#"<text x="{}" y="{}" id="s{}" "#, p.0, p.1, id)?;
         match x {
             Ordering::Greater => write!(buf, r#"text-anchor="start" "#)?,
@@ -78,7 +105,20 @@ impl Stop {
             Ordering::Equal => write!(buf, r#"dominant-baseline="middle" "#)?,
             Ordering::Less => write!(buf, r#"dominant-baseline="alphabetic" "#)?,
         };
-        writeln!(buf, ">{}</text>", label)?;
+        match doc {
+            Some(doc) => {
+                writeln!(buf, ">")?;
+                format_doc(buf, doc)?;
+                writeln!(buf, "{}</text>", label)?;
+            },
+            None => writeln!(buf, ">{}</text>", label)?,
+        }
         Ok(())
     }
 }
+
+fn intersect(a: Line, b: Line) -> Result<Point, Box<Error>> {
+    a.intersect(b).ok_or_else(|| {
+        errors::Error::parallel(format!("{}", a).as_ref(), format!("{}", b).as_ref()).into()
+    })
+}